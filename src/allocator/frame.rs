@@ -0,0 +1,70 @@
+//! Owned physical frames, given back to the page allocator when dropped.
+
+use core::{mem, slice};
+
+use super::{PAGE_ALLOCATOR, PAGE_SIZE};
+use crate::arch::mmu;
+
+/// A block of `2^order` contiguous frames, aligned to its size.
+pub struct FrameRange {
+    start: usize,
+    order: usize,
+}
+
+impl FrameRange {
+    /// Allocates `2^order` frames. Their contents are whatever was left there.
+    pub fn alloc(order: usize) -> Option<Self> {
+        let start = PAGE_ALLOCATOR.get().lock().alloc(order)?;
+        Some(Self { start, order })
+    }
+
+    /// The address of the first frame, in the linear map.
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn len(&self) -> usize {
+        PAGE_SIZE << self.order
+    }
+
+    pub fn end(&self) -> usize {
+        self.start + self.len()
+    }
+
+    pub fn as_mut_bytes(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.start as *mut u8, self.len()) }
+    }
+
+    /// Gives up ownership without freeing the frames, and returns their address.
+    pub fn leak(self) -> usize {
+        let start = self.start;
+        mem::forget(self);
+        start
+    }
+}
+
+impl Drop for FrameRange {
+    fn drop(&mut self) {
+        unsafe { PAGE_ALLOCATOR.get().lock().free(self.start, self.order) }
+    }
+}
+
+/// A single frame.
+pub struct PhysFrame(FrameRange);
+
+impl PhysFrame {
+    /// Allocates a frame filled with zeros.
+    pub fn alloc_zeroed() -> Option<Self> {
+        let mut frame = Self(FrameRange::alloc(0)?);
+        frame.as_mut_bytes().fill(0);
+        Some(frame)
+    }
+
+    pub fn phys_addr(&self) -> usize {
+        mmu::virt_to_phys(self.0.start())
+    }
+
+    pub fn as_mut_bytes(&mut self) -> &mut [u8] {
+        self.0.as_mut_bytes()
+    }
+}
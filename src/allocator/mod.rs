@@ -1,78 +1,259 @@
-use spin::mutex::SpinMutex;
+//! Physical frame allocator.
+//!
+//! A buddy allocator over 4 KiB frames. A block of order `n` is `2^n` contiguous frames aligned to
+//! its own size, and its buddy is the block it was split from. Free blocks are kept on a
+//! doubly-linked list per order, stored in the free memory itself, and freeing a block merges it
+//! with its buddy for as long as the buddy is free too.
+//!
+//! Addresses are kernel virtual addresses in the linear map. Most of the kernel allocates through
+//! [`FrameRange`] and [`PhysFrame`], which free their frames when dropped.
+
+use core::ptr::NonNull;
 
 use crate::{
+    arch::mmu,
     singleton::Singleton,
+    sync::IrqSpinLock,
     utils::{align_down, align_up},
 };
 
-pub const PAGE_SIZE: usize = 4 * (1 << 20); // 4MB
+mod frame;
+
+pub use frame::{FrameRange, PhysFrame};
+
+pub const PAGE_SIZE: usize = mmu::PAGE_SIZE; // 4KB
+
+/// Order of the largest block, `2^MAX_ORDER` frames (16MB).
+pub const MAX_ORDER: usize = 12;
+
+const MAX_BLOCK_SIZE: usize = PAGE_SIZE << MAX_ORDER;
+
+/// Set in the state of the first frame of a free block, whose order is in the low bits.
+const FREE: u8 = 0x80;
+/// Set in the state of the first frame of an allocated block, whose order is in the low bits.
+/// Every other frame is reserved or inside a block.
+const ALLOCATED: u8 = 0x40;
 
 pub struct PageAllocator {
-    start: usize,
+    /// Address of frame 0, aligned to the largest block so that buddies are naturally aligned.
+    /// Frames before the managed region are never free.
+    base: usize,
+    /// One byte per frame from `base` up to the end of the managed region.
+    state: &'static mut [u8],
+    free_lists: [Option<NonNull<FreeBlock>>; MAX_ORDER + 1],
     num_pages: usize,
-    page_used: &'static mut [bool],
+    free_pages: usize,
+    allocated_blocks: [usize; MAX_ORDER + 1],
+    free_blocks: [usize; MAX_ORDER + 1],
+}
+
+/// A snapshot of the page allocator.
+pub struct PageStats {
+    pub num_pages: usize,
+    /// Frames that are allocated or reserved, including the ones holding the frame states.
+    pub used_pages: usize,
+    /// Allocated blocks, by order.
+    pub allocated_blocks: [usize; MAX_ORDER + 1],
+    /// Free blocks, by order.
+    pub free_blocks: [usize; MAX_ORDER + 1],
 }
 
-pub static PAGE_ALLOCATOR: Singleton<SpinMutex<PageAllocator>> = Singleton::new();
+// The free lists only point into memory owned by the allocator.
+unsafe impl Send for PageAllocator {}
 
-struct Page {
-    addr: usize,
+pub static PAGE_ALLOCATOR: Singleton<IrqSpinLock<PageAllocator>> = Singleton::new();
+
+/// Written at the start of every free block.
+struct FreeBlock {
+    prev: Option<NonNull<FreeBlock>>,
+    next: Option<NonNull<FreeBlock>>,
 }
 
-impl PageAllocator {
-    pub fn new(start: usize, size: usize) -> Self {
-        let end = start + size;
+/// Returns the smallest order whose blocks hold `size` bytes.
+pub fn order_for(size: usize) -> usize {
+    let frames = align_up(size.max(1), PAGE_SIZE) / PAGE_SIZE;
+    frames.next_power_of_two().trailing_zeros() as usize
+}
 
+impl PageAllocator {
+    /// Manages `[start, start + size)`. The frame states are kept at the start of it. The frames
+    /// overlapping the `[start, end)` ranges in `reserved` are never freed, so memory handed over
+    /// by the bootloader is neither given out nor written to.
+    pub fn new(start: usize, size: usize, reserved: &[(usize, usize)]) -> Self {
+        let end = align_down(start + size, PAGE_SIZE);
         let start = align_up(start, PAGE_SIZE);
-        let end = align_down(end, PAGE_SIZE);
-        let num_pages = (end - start) / PAGE_SIZE;
-        let num_pages_for_num_pages_bool = (num_pages + PAGE_SIZE - 1) / PAGE_SIZE;
-        let page_used = unsafe { core::slice::from_raw_parts_mut(start as *mut bool, num_pages) };
-        for i in 0..num_pages_for_num_pages_bool {
-            page_used[i] = true;
+        let base = align_down(start, MAX_BLOCK_SIZE);
+
+        let num_frames = (end - base) / PAGE_SIZE;
+        let state = unsafe { core::slice::from_raw_parts_mut(start as *mut u8, num_frames) };
+        state.fill(0);
+
+        let mut allocator = Self {
+            base,
+            state,
+            free_lists: [None; MAX_ORDER + 1],
+            num_pages: (end - start) / PAGE_SIZE,
+            free_pages: 0,
+            allocated_blocks: [0; MAX_ORDER + 1],
+            free_blocks: [0; MAX_ORDER + 1],
+        };
+        // The frames overlapping a reserved range, as `[first, last)`.
+        let reserved_frames = |(first, last): (usize, usize)| {
+            let first = align_down(first, PAGE_SIZE).clamp(base, end);
+            let last = align_up(last, PAGE_SIZE).clamp(base, end);
+            ((first - base) / PAGE_SIZE, (last - base) / PAGE_SIZE)
+        };
+        let mut frame = allocator.frame_index(align_up(start + num_frames, PAGE_SIZE));
+        while frame < num_frames {
+            let next = reserved
+                .iter()
+                .map(|&range| reserved_frames(range))
+                .filter(|&(_, last)| last > frame)
+                .min_by_key(|&(first, _)| first);
+            match next {
+                Some((first, last)) => {
+                    allocator.free_range(frame, first);
+                    frame = last;
+                }
+                None => {
+                    allocator.free_range(frame, num_frames);
+                    break;
+                }
+            }
         }
+        allocator
+    }
 
-        Self {
-            start,
-            num_pages,
-            page_used,
+    pub fn stats(&self) -> PageStats {
+        PageStats {
+            num_pages: self.num_pages,
+            used_pages: self.num_pages - self.free_pages,
+            allocated_blocks: self.allocated_blocks,
+            free_blocks: self.free_blocks,
         }
     }
 
-    pub fn page_addr(&self, n: usize) -> usize {
-        self.start + n * PAGE_SIZE
+    /// Allocates `2^order` contiguous frames, aligned to their size.
+    pub fn alloc(&mut self, order: usize) -> Option<usize> {
+        let mut block_order = (order..=MAX_ORDER).find(|&n| self.free_lists[n].is_some())?;
+        let frame = self.frame_index(self.free_lists[block_order].unwrap().as_ptr() as usize);
+        self.remove(frame, block_order);
+        while block_order > order {
+            block_order -= 1;
+            self.push(frame + (1 << block_order), block_order);
+        }
+        self.state[frame] = ALLOCATED | order as u8;
+        self.allocated_blocks[order] += 1;
+        Some(self.frame_addr(frame))
     }
 
-    pub fn get_page(&mut self) -> Option<Page> {
-        for i in 0..self.num_pages {
-            if !self.page_used[i] {
-                self.page_used[i] = true;
-                return Some(Page {
-                    addr: self.page_addr(i),
-                });
+    /// Frees a block returned by [`alloc`](Self::alloc) with the same order.
+    ///
+    /// # Safety
+    ///
+    /// The block must not be used after this. Debug builds panic if it is not an allocated block
+    /// of `order`, e.g. because it was already freed.
+    pub unsafe fn free(&mut self, addr: usize, order: usize) {
+        #[cfg(debug_assertions)]
+        self.check_allocated(addr, order);
+
+        let mut frame = self.frame_index(addr);
+        self.state[frame] = 0;
+        self.allocated_blocks[order] -= 1;
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = frame ^ (1 << order);
+            if self.state.get(buddy) != Some(&(FREE | order as u8)) {
+                break;
             }
+            self.remove(buddy, order);
+            frame = frame.min(buddy);
+            order += 1;
+        }
+        self.push(frame, order);
+    }
+
+    #[cfg(debug_assertions)]
+    fn check_allocated(&self, addr: usize, order: usize) {
+        let end = self.frame_addr(self.state.len());
+        if addr < self.base || addr >= end || addr % (PAGE_SIZE << order) != 0 {
+            panic!(
+                "freeing {:#x} of order {}, which is not a block in {:#x}..{:#x}",
+                addr, order, self.base, end
+            );
+        }
+        let state = self.state[self.frame_index(addr)];
+        if state != ALLOCATED | order as u8 {
+            let actual = if state & FREE != 0 {
+                "free"
+            } else if state & ALLOCATED != 0 {
+                "allocated with another order"
+            } else {
+                "reserved or inside another block"
+            };
+            panic!(
+                "freeing {:#x} of order {}, which is {}",
+                addr, order, actual
+            );
         }
+    }
+
+    fn frame_index(&self, addr: usize) -> usize {
+        (addr - self.base) / PAGE_SIZE
+    }
+
+    fn frame_addr(&self, frame: usize) -> usize {
+        self.base + frame * PAGE_SIZE
+    }
 
-        None
+    /// Frees `[first, last)` in the largest blocks that fit.
+    fn free_range(&mut self, first: usize, last: usize) {
+        let mut frame = first;
+        while frame < last {
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|&n| frame % (1 << n) == 0 && frame + (1 << n) <= last)
+                .unwrap();
+            self.push(frame, order);
+            frame += 1 << order;
+        }
     }
 
-    pub fn get_n_pages(&mut self, n: usize) -> Option<usize> {
-        if self.num_pages < n {
-            return None;
+    fn push(&mut self, frame: usize, order: usize) {
+        let node = NonNull::new(self.frame_addr(frame) as *mut FreeBlock).unwrap();
+        let next = self.free_lists[order];
+        unsafe {
+            node.as_ptr().write(FreeBlock { prev: None, next });
+            if let Some(next) = next {
+                (*next.as_ptr()).prev = Some(node);
+            }
         }
-        let end = self.num_pages - n;
-        for i in 0..=end {
-            if self.page_used[i..i + n].iter().all(|x| !*x) {
-                self.page_used[i..i + n].fill(true);
-                return Some(self.page_addr(i));
+        self.free_lists[order] = Some(node);
+        self.state[frame] = FREE | order as u8;
+        self.free_pages += 1 << order;
+        self.free_blocks[order] += 1;
+    }
+
+    fn remove(&mut self, frame: usize, order: usize) {
+        let node = self.frame_addr(frame) as *mut FreeBlock;
+        unsafe {
+            let FreeBlock { prev, next } = node.read();
+            match prev {
+                Some(prev) => (*prev.as_ptr()).next = next,
+                None => self.free_lists[order] = next,
+            }
+            if let Some(next) = next {
+                (*next.as_ptr()).prev = prev;
             }
         }
-        None
+        self.state[frame] = 0;
+        self.free_pages -= 1 << order;
+        self.free_blocks[order] -= 1;
     }
 }
 
-pub unsafe fn page_allocator_init(start: usize, size: usize) {
+pub unsafe fn page_allocator_init(start: usize, size: usize, reserved: &[(usize, usize)]) {
     unsafe {
-        PAGE_ALLOCATOR.init(SpinMutex::new(PageAllocator::new(start, size)));
+        PAGE_ALLOCATOR.init(IrqSpinLock::new(PageAllocator::new(start, size, reserved)));
     }
 }
@@ -0,0 +1,272 @@
+//! User address spaces.
+//!
+//! An [`AddressSpace`] owns the translation tables of the lower half of the virtual address space
+//! and the frames mapped there. It is loaded into TTBR0_EL1 while one of its threads runs. Its
+//! pages are not global and are tagged with an ASID, so switching between address spaces needs no
+//! TLB maintenance.
+
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use core::{fmt, ops::Range};
+
+use cortex_a::{asm::barrier, registers::*};
+
+use super::mmu::{
+    self, MemoryKind, PageTable, DESC_VALID, ENTRIES_PER_TABLE, OUTPUT_ADDR_MASK, PAGE_SHIFT,
+    PAGE_SIZE, TCR_EPD0,
+};
+use crate::{
+    allocator::PhysFrame,
+    sync::{Mutex, SpinMutex},
+    utils::align_up,
+};
+
+/// End of the part of the virtual address space that is available to user tasks.
+pub const USER_END: usize = 1 << 48;
+
+/// Where [`AddressSpace::mmap`] places the first mapping.
+const MMAP_BASE: usize = 0x10_0000_0000;
+
+/// TCR_EL1.AS is clear, so ASIDs are 8 bits wide.
+const ASID_COUNT: usize = 256;
+
+/// Allocated ASIDs, one bit each. ASID 0 is reserved for when no address space is loaded.
+static ASIDS: SpinMutex<[u64; ASID_COUNT / 64]> = SpinMutex::new([1, 0, 0, 0]);
+
+fn alloc_asid() -> Option<u16> {
+    let mut asids = ASIDS.lock();
+    let word = asids.iter().position(|&bits| bits != u64::MAX)?;
+    let bit = asids[word].trailing_ones() as usize;
+    asids[word] |= 1 << bit;
+    Some((word * 64 + bit) as u16)
+}
+
+fn free_asid(asid: u16) {
+    ASIDS.lock()[asid as usize / 64] &= !(1 << (asid % 64));
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MapError {
+    /// Out of memory for frames or translation tables.
+    OutOfMemory,
+    /// Every ASID is taken by another address space.
+    OutOfAsids,
+    /// The range is empty, not page aligned or not below [`USER_END`].
+    InvalidRange,
+    /// Part of the range is already mapped.
+    AlreadyMapped,
+    /// Part of the range is not mapped.
+    NotMapped,
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::OutOfMemory => write!(f, "out of memory"),
+            MapError::OutOfAsids => write!(f, "out of ASIDs"),
+            MapError::InvalidRange => write!(f, "invalid range"),
+            MapError::AlreadyMapped => write!(f, "already mapped"),
+            MapError::NotMapped => write!(f, "not mapped"),
+        }
+    }
+}
+
+fn check_range(va: usize, len: usize) -> Result<(), MapError> {
+    let aligned = va % PAGE_SIZE == 0 && len % PAGE_SIZE == 0;
+    match va.checked_add(len) {
+        Some(end) if aligned && len > 0 && end <= USER_END => Ok(()),
+        _ => Err(MapError::InvalidRange),
+    }
+}
+
+struct Mappings {
+    l0: Box<PageTable>,
+    /// Level 1 to 3 tables. They are only reached through the descriptors of their parents.
+    tables: Vec<Box<PageTable>>,
+    /// The frames backing the mapped pages, by virtual address.
+    frames: BTreeMap<usize, PhysFrame>,
+    /// Where the next [`AddressSpace::mmap`] goes.
+    mmap_next: usize,
+}
+
+impl Mappings {
+    /// Returns the level 3 descriptor of `va`, allocating the tables on the way if needed.
+    fn leaf_entry(&mut self, va: usize) -> Result<&mut u64, MapError> {
+        let mut table: *mut PageTable = &mut *self.l0;
+        for shift in [39, 30, 21] {
+            let entry = unsafe { &mut (*table).entries[(va >> shift) % ENTRIES_PER_TABLE] };
+            if *entry & DESC_VALID != 0 {
+                let next = mmu::phys_to_virt((*entry & OUTPUT_ADDR_MASK) as usize);
+                table = next as *mut PageTable;
+            } else {
+                let mut next =
+                    Box::try_new(PageTable::empty()).map_err(|_| MapError::OutOfMemory)?;
+                *entry = mmu::table_desc(&next);
+                table = &mut *next;
+                self.tables.push(next);
+            }
+        }
+        Ok(unsafe { &mut (*table).entries[(va >> PAGE_SHIFT) % ENTRIES_PER_TABLE] })
+    }
+
+    /// The lowest mapped page in `range`.
+    fn first_page(&self, range: Range<usize>) -> Option<usize> {
+        self.frames.range(range).next().map(|(&page, _)| page)
+    }
+
+    /// Maps the unmapped range `[va, va + len)`. On failure, the pages mapped so far stay mapped.
+    fn map_zeroed(&mut self, va: usize, len: usize, kind: MemoryKind) -> Result<(), MapError> {
+        for page in (va..va + len).step_by(PAGE_SIZE) {
+            let frame = PhysFrame::alloc_zeroed().ok_or(MapError::OutOfMemory)?;
+            *self.leaf_entry(page)? = mmu::page_desc(frame.phys_addr(), kind);
+            self.frames.insert(page, frame);
+        }
+        // The pages were unmapped, so there are no stale TLB entries. Only the walkers need to
+        // see the new descriptors.
+        barrier::dsb(barrier::ISHST);
+        Ok(())
+    }
+}
+
+pub struct AddressSpace {
+    asid: u16,
+    ttbr0: u64,
+    mappings: Mutex<Mappings>,
+}
+
+impl AddressSpace {
+    /// Creates an empty address space.
+    pub fn new() -> Result<Self, MapError> {
+        let l0 = Box::try_new(PageTable::empty()).map_err(|_| MapError::OutOfMemory)?;
+        let asid = alloc_asid().ok_or(MapError::OutOfAsids)?;
+        Ok(Self {
+            asid,
+            ttbr0: l0.phys_addr() | (asid as u64) << 48,
+            mappings: Mutex::new(Mappings {
+                l0,
+                tables: Vec::new(),
+                frames: BTreeMap::new(),
+                mmap_next: MMAP_BASE,
+            }),
+        })
+    }
+
+    /// Maps `[va, va + len)` to newly allocated, zeroed frames. On failure, nothing is left
+    /// mapped.
+    pub fn map_zeroed(&self, va: usize, len: usize, kind: MemoryKind) -> Result<(), MapError> {
+        check_range(va, len)?;
+        self.map_zeroed_locked(&mut self.mappings.lock(), va, len, kind)
+    }
+
+    /// Maps `[va, va + len)`, and unmaps it again if it runs out of memory partway.
+    fn map_zeroed_locked(
+        &self,
+        mappings: &mut Mappings,
+        va: usize,
+        len: usize,
+        kind: MemoryKind,
+    ) -> Result<(), MapError> {
+        if mappings.first_page(va..va + len).is_some() {
+            return Err(MapError::AlreadyMapped);
+        }
+        if let Err(error) = mappings.map_zeroed(va, len, kind) {
+            // Everything mapped in the range is from this call, and its tables exist.
+            self.unmap_locked(mappings, va, len)
+                .expect("mapped pages have tables");
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    /// Maps `len` bytes of zeroed memory at an address of the kernel's choosing, and returns it.
+    pub fn mmap(&self, len: usize, kind: MemoryKind) -> Result<usize, MapError> {
+        let len = align_up(len.min(USER_END), PAGE_SIZE);
+        let mut mappings = self.mappings.lock();
+        let va = mappings.mmap_next;
+        check_range(va, len)?;
+        self.map_zeroed_locked(&mut mappings, va, len, kind)?;
+        mappings.mmap_next = va + len;
+        Ok(va)
+    }
+
+    /// Unmaps `[va, va + len)` and frees the frames that were mapped there.
+    pub fn unmap(&self, va: usize, len: usize) -> Result<(), MapError> {
+        check_range(va, len)?;
+        self.unmap_locked(&mut self.mappings.lock(), va, len)
+    }
+
+    /// Unmaps `[va, va + len)`. Does not allocate, so it also works when out of memory.
+    fn unmap_locked(&self, mappings: &mut Mappings, va: usize, len: usize) -> Result<(), MapError> {
+        let end = va + len;
+        let mut next = va;
+        while let Some(page) = mappings.first_page(next..end) {
+            *mappings.leaf_entry(page)? = 0;
+            next = page + PAGE_SIZE;
+        }
+        // Other cores may still be running threads of this address space.
+        mmu::flush_tlb_asid_range(self.asid, va, len);
+        while let Some(page) = mappings.first_page(va..end) {
+            mappings.frames.remove(&page);
+        }
+        Ok(())
+    }
+
+    /// Copies `data` to `va` through the kernel's mapping of the frames, regardless of the
+    /// permissions of the user mapping. Used to load code and data before a task starts.
+    pub fn write(&self, va: usize, data: &[u8]) -> Result<(), MapError> {
+        let mut mappings = self.mappings.lock();
+        let mut copied = 0;
+        while copied < data.len() {
+            let addr = va.checked_add(copied).ok_or(MapError::InvalidRange)?;
+            let offset = addr % PAGE_SIZE;
+            let n = (PAGE_SIZE - offset).min(data.len() - copied);
+            let frame = mappings
+                .frames
+                .get_mut(&(addr - offset))
+                .ok_or(MapError::NotMapped)?;
+            let dst = &mut frame.as_mut_bytes()[offset..offset + n];
+            dst.copy_from_slice(&data[copied..copied + n]);
+            sync_icache(dst.as_ptr() as usize, n);
+            copied += n;
+        }
+        Ok(())
+    }
+
+    /// Loads the address space into TTBR0_EL1 of the executing core.
+    pub fn activate(&self) {
+        TTBR0_EL1.set(self.ttbr0);
+        TCR_EL1.set(TCR_EL1.get() & !TCR_EPD0);
+        barrier::isb(barrier::SY);
+    }
+}
+
+/// Leaves the lower half of the address space unmapped on the executing core.
+pub fn deactivate() {
+    TCR_EL1.set(TCR_EL1.get() | TCR_EPD0);
+    // Also switch to the reserved ASID, so TLB entries of the previous address space stop matching.
+    TTBR0_EL1.set(0);
+    barrier::isb(barrier::SY);
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        // The tables and frames are freed right after, and the ASID may be reused.
+        mmu::flush_tlb_asid(self.asid);
+        free_asid(self.asid);
+    }
+}
+
+/// Makes `[addr, addr + len)`, just written through the data cache, visible to instruction fetches.
+fn sync_icache(addr: usize, len: usize) {
+    let ctr: usize;
+    unsafe { core::arch::asm!("mrs {}, ctr_el0", out(reg) ctr, options(nostack)) };
+    // CTR_EL0.DminLine is the log2 of the smallest data cache line, in words.
+    let line = 4 << ((ctr >> 16) & 0xf);
+    let start = addr & !(line - 1);
+    unsafe {
+        for line_addr in (start..addr + len).step_by(line) {
+            core::arch::asm!("dc cvau, {}", in(reg) line_addr, options(nostack));
+        }
+        // The instruction cache may be indexed by virtual address, so invalidate all of it.
+        core::arch::asm!("dsb ish", "ic ialluis", "dsb ish", "isb", options(nostack));
+    }
+}
@@ -0,0 +1,39 @@
+//! Walks the frame records on the stack.
+//!
+//! Every function compiled with frame pointers saves a record of the caller's frame pointer and its
+//! return address, and points x29 at it, so the records form a list up the stack.
+
+use crate::thread::Thread;
+
+/// Fills `addresses` with the return addresses of the calling functions, innermost first, and
+/// returns how many there were.
+///
+/// Only 16-byte aligned records on the current thread's kernel stack are followed, so a frame
+/// pointer that is garbage, e.g. in code built without frame pointers, is never dereferenced.
+/// Stops at the first other one, e.g. the user frame pointer a system call was made with. Nothing
+/// is recorded on the boot stacks.
+#[inline(never)]
+pub fn return_addresses(addresses: &mut [usize]) -> usize {
+    let stack = match Thread::current_stack() {
+        Some(stack) => stack,
+        None => return 0,
+    };
+    let mut fp: usize;
+    unsafe { core::arch::asm!("mov {}, x29", out(reg) fp, options(nomem, nostack)) };
+
+    let mut count = 0;
+    while count < addresses.len() && stack.start <= fp && fp + 16 <= stack.end && fp % 16 == 0 {
+        let (next, lr) = unsafe { (*(fp as *const usize), *((fp + 8) as *const usize)) };
+        if lr == 0 {
+            break;
+        }
+        addresses[count] = lr;
+        count += 1;
+        // The stack grows down, so callers' records are at higher addresses.
+        if next <= fp {
+            break;
+        }
+        fp = next;
+    }
+    count
+}
@@ -1 +1,10 @@
 core::arch::global_asm!(include_str!("boot.s"));
+
+/// The exception level `_start` was entered at, stored by boot.s before dropping to EL1.
+#[no_mangle]
+static mut __EXT_BOOT_EL: u64 = 0;
+
+/// Returns the exception level the kernel was entered at.
+pub fn boot_el() -> u64 {
+    unsafe { __EXT_BOOT_EL }
+}
@@ -1,21 +1,213 @@
 .globl _start
+.globl __secondary_start
 .extern __EXT_FDT_PTR
+.extern __EXT_BOOT_EL
+.extern __EXT_KERNEL_TTBR1
 .extern __EXT_STACK_END
+.extern __EXT_BSS_START
+.extern __EXT_BSS_END_INCLUSIVE
+
+// The kernel is linked in the higher half but entered at its physical load address with the MMU
+// off, so everything before `_start_high` must only use PC-relative addressing.
+
+// MAIR_EL1 attribute indices must match `MAIR_IDX_*` in mmu.rs.
+.equ MAIR_VALUE,            0x04ff                  // Attr0 = normal WB RW-alloc, Attr1 = Device-nGnRE
+.equ TCR_VALUE,             0xb5103510              // T0SZ = T1SZ = 16, 4 KiB granules, inner shareable WB
+.equ SCTLR_MMU_ON,          (1 << 0) | (1 << 2) | (1 << 12)   // M, C, I
+
+.equ SCR_EL3_VALUE,         (1 << 10) | (1 << 8) | (3 << 4) | (1 << 0)   // RW, HCE, RES1, NS
+.equ SPSR_EL2H_MASKED,      0x3c9                   // EL2h, DAIF masked
+.equ SPSR_EL1H_MASKED,      0x3c5                   // EL1h, DAIF masked
+.equ HCR_EL2_RW,            (1 << 31)               // EL1 is AArch64
+.equ CNTHCTL_EL1PCTEN_EL1PCEN, 0x3                  // EL1 may use the physical counter and timer
+.equ CPTR_EL2_DEFAULT,      0x32ff                  // RES1 bits only: no FP/SIMD or SVE traps
+.equ SCTLR_EL1_DEFAULT,     0x30d00800              // RES1 bits only: MMU and caches off, little endian
+
+.equ DESC_TABLE,            0x3
+.equ DESC_L1_NORMAL,        0x701                   // Block, AttrIndx = 0, inner shareable, AF
+.equ DESC_L1_DEVICE,        0x0060000000000405      // Block, AttrIndx = 1, AF, PXN, UXN
 
 .section ".text.boot"
 
 _start:
-    ldr     x30, =__EXT_FDT_PTR
-    str     x0, [x30]
+    // Keep the FDT pointer and the entry EL in callee-saved registers until memory is set up.
+    mov     x19, x0
+    mrs     x20, CurrentEL
+    lsr     x20, x20, #2
+
+    bl      __drop_to_el1
+
+    // Zero the bss. The early page tables live there too.
+    adrp    x1, __EXT_BSS_START
+    add     x1, x1, :lo12:__EXT_BSS_START
+    adrp    x2, __EXT_BSS_END_INCLUSIVE
+    add     x2, x2, :lo12:__EXT_BSS_END_INCLUSIVE
+1:  str     xzr, [x1], #8
+    cmp     x1, x2
+    b.ls    1b
+
+    adrp    x1, __EXT_FDT_PTR
+    str     x19, [x1, :lo12:__EXT_FDT_PTR]
+    adrp    x1, __EXT_BOOT_EL
+    str     x20, [x1, :lo12:__EXT_BOOT_EL]
+
+    bl      __early_mmu_enable
+
+    // Continue at the link address of `_start_high`, now through TTBR1.
+    ldr     x1, =_start_high
+    br      x1
+
+_start_high:
     ldr     x30, =__EXT_STACK_END
     mov     sp, x30
     bl      _main
 
-// TODO: get PSCI address from FDT instead
-.equ PSCI_SYSTEM_OFF, 0x84000008
-.globl __system_off
-__system_off:
-    ldr     x0, =PSCI_SYSTEM_OFF
-    hvc     #0
+// Entry point of secondary cores, started by `smp::smp_init` through PSCI `CPU_ON` with the
+// virtual address of their `Cpu` in x0.
+__secondary_start:
+    mov     x19, x0
+    mrs     x20, CurrentEL
+    lsr     x20, x20, #2
+
+    bl      __drop_to_el1
+
+    // Keep the early identity map in TTBR0 until we run from the higher half. The kernel tables
+    // were built by the boot core.
+    adrp    x1, __early_l0
+    adrp    x2, __EXT_KERNEL_TTBR1
+    ldr     x2, [x2, :lo12:__EXT_KERNEL_TTBR1]
+    bl      __mmu_enable
+
+    ldr     x1, =_secondary_high
+    br      x1
+
+_secondary_high:
+    ldr     x1, [x19]                               // Cpu::boot_stack_top
+    mov     sp, x1
+    mov     x0, x19
+    bl      _secondary_main
+
+// Returns to the caller in EL1h, with DAIF masked, if entered at EL2 or EL3.
+//
+// `x20` holds the current EL. U-Boot on the Pinephone and QEMU with `virtualization=on` enter the
+// kernel at EL2; QEMU with `secure=on` and no firmware enters at EL3.
+__drop_to_el1:
+    cmp     x20, #3
+    b.eq    3f
+    cmp     x20, #2
+    b.eq    2f
+    ret
+
+    // EL3: lower ELs are non-secure and AArch64. Continue at EL2.
+3:  ldr     x1, =SCR_EL3_VALUE
+    msr     SCR_EL3, x1
+    mov     x1, #SPSR_EL2H_MASKED
+    msr     SPSR_EL3, x1
+    adr     x1, 2f
+    msr     ELR_EL3, x1
+    eret
+
+    // EL2: set up a plain AArch64 EL1 without traps and without a virtual CPU identity.
+2:  mov     x1, #HCR_EL2_RW
+    msr     HCR_EL2, x1
+
+    mrs     x1, CNTHCTL_EL2
+    orr     x1, x1, #CNTHCTL_EL1PCTEN_EL1PCEN
+    msr     CNTHCTL_EL2, x1
+    msr     CNTVOFF_EL2, xzr
+
+    mov     x1, #CPTR_EL2_DEFAULT
+    msr     CPTR_EL2, x1
+    msr     HSTR_EL2, xzr
+
+    // With a GICv3, let EL1 use the system register CPU interface.
+    mrs     x1, ID_AA64PFR0_EL1
+    ubfx    x1, x1, #24, #4
+    cbz     x1, 1f
+    mrs     x1, S3_4_C12_C9_5                       // ICC_SRE_EL2
+    mov     x2, #0x9                                // Enable, SRE
+    orr     x1, x1, x2
+    msr     S3_4_C12_C9_5, x1
+    isb
+
+1:  mrs     x1, MIDR_EL1
+    msr     VPIDR_EL2, x1
+    mrs     x1, MPIDR_EL1
+    msr     VMPIDR_EL2, x1
+
+    ldr     x1, =SCTLR_EL1_DEFAULT
+    msr     SCTLR_EL1, x1
+
+    mov     x1, #SPSR_EL1H_MASKED
+    msr     SPSR_EL2, x1
+    msr     ELR_EL2, x30
+    eret
+
+// Builds the early translation tables and turns on the MMU.
+//
+// The same two tables are installed in TTBR0 and TTBR1, mapping the first GiB of the physical
+// address space as device memory and the GiB the kernel was loaded into as normal memory. This
+// keeps the trampoline running after the MMU is on, and also covers the FDT, which QEMU and
+// U-Boot place close to the kernel. `mmu::mmu_init` replaces these tables with the final ones.
+__early_mmu_enable:
+    adrp    x1, __early_l0
+    adrp    x2, __early_l1
+
+    // L0[0] -> L1
+    orr     x3, x2, #DESC_TABLE
+    str     x3, [x1]
+
+    // L1[0]: MMIO
+    ldr     x3, =DESC_L1_DEVICE
+    str     x3, [x2]
+
+    // L1[n]: the GiB containing the kernel image
+    adr     x4, _start
+    lsr     x4, x4, #30
+    lsl     x5, x4, #30
+    ldr     x3, =DESC_L1_NORMAL
+    orr     x3, x3, x5
+    str     x3, [x2, x4, lsl #3]
+
+    // The tables were written with the MMU off. Drop any stale cache lines covering them.
+    mov     x3, x1
+    add     x4, x1, #(2 * 4096)
+2:  dc      ivac, x3
+    add     x3, x3, #64
+    cmp     x3, x4
+    b.lo    2b
+    dsb     sy
+
+    mov     x2, x1
+    b       __mmu_enable
+
+// Turns on the MMU with x1 in TTBR0_EL1 and x2 in TTBR1_EL1.
+__mmu_enable:
+    ldr     x3, =MAIR_VALUE
+    msr     MAIR_EL1, x3
+    ldr     x3, =TCR_VALUE
+    mrs     x4, ID_AA64MMFR0_EL1
+    bfi     x3, x4, #32, #3                         // IPS = PARange
+    msr     TCR_EL1, x3
+    msr     TTBR0_EL1, x1
+    msr     TTBR1_EL1, x2
+    isb
+    tlbi    vmalle1
+    dsb     nsh
+    isb
+
+    mrs     x3, SCTLR_EL1
+    ldr     x4, =SCTLR_MMU_ON
+    orr     x3, x3, x4
+    msr     SCTLR_EL1, x3
+    isb
+    ret
+
+.section ".bss"
+.balign 4096
+__early_l0:
+    .space  4096
+__early_l1:
+    .space  4096
 
 // vim: filetype=arm
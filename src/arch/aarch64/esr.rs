@@ -0,0 +1,200 @@
+//! Decoding of the exception syndrome register (ESR_EL1).
+
+use core::fmt;
+
+const EC_SHIFT: u64 = 26;
+const EC_MASK: u64 = 0x3f;
+const ISS_MASK: u64 = 0x1ff_ffff;
+
+// Exception classes.
+const EC_UNKNOWN: u64 = 0x00;
+const EC_TRAPPED_WFI_WFE: u64 = 0x01;
+const EC_TRAPPED_FP: u64 = 0x07;
+const EC_ILLEGAL_EXECUTION_STATE: u64 = 0x0e;
+const EC_SVC64: u64 = 0x15;
+const EC_TRAPPED_MSR_MRS: u64 = 0x18;
+const EC_INSTR_ABORT_LOWER_EL: u64 = 0x20;
+const EC_INSTR_ABORT_CURRENT_EL: u64 = 0x21;
+const EC_PC_ALIGNMENT_FAULT: u64 = 0x22;
+const EC_DATA_ABORT_LOWER_EL: u64 = 0x24;
+const EC_DATA_ABORT_CURRENT_EL: u64 = 0x25;
+const EC_SP_ALIGNMENT_FAULT: u64 = 0x26;
+const EC_SERROR: u64 = 0x2f;
+const EC_BRK64: u64 = 0x3c;
+
+// Data and instruction abort ISS fields.
+const ISS_FSC_MASK: u64 = 0x3f;
+const ISS_WNR: u64 = 1 << 6;
+
+/// Where an exception came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Origin {
+    CurrentEl,
+    LowerEl,
+}
+
+/// Fault status code of a data or instruction abort (DFSC/IFSC).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultStatus {
+    AddressSize { level: u8 },
+    Translation { level: u8 },
+    AccessFlag { level: u8 },
+    Permission { level: u8 },
+    SynchronousExternal,
+    Alignment,
+    TlbConflict,
+    Other(u8),
+}
+
+impl FaultStatus {
+    fn decode(fsc: u64) -> Self {
+        let level = (fsc & 0b11) as u8;
+        match fsc {
+            0b00_0000..=0b00_0011 => FaultStatus::AddressSize { level },
+            0b00_0100..=0b00_0111 => FaultStatus::Translation { level },
+            0b00_1000..=0b00_1011 => FaultStatus::AccessFlag { level },
+            0b00_1100..=0b00_1111 => FaultStatus::Permission { level },
+            0b01_0000 => FaultStatus::SynchronousExternal,
+            0b10_0001 => FaultStatus::Alignment,
+            0b11_0000 => FaultStatus::TlbConflict,
+            _ => FaultStatus::Other(fsc as u8),
+        }
+    }
+}
+
+/// A synchronous exception or SError, decoded from ESR_EL1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExceptionKind {
+    /// An undefined or unallocated instruction.
+    UndefinedInstruction,
+    TrappedWfiWfe,
+    /// SIMD or floating point access while disabled by CPACR_EL1.
+    TrappedFp,
+    IllegalExecutionState,
+    Svc {
+        imm: u16,
+    },
+    TrappedSysReg,
+    InstructionAbort {
+        origin: Origin,
+        status: FaultStatus,
+    },
+    DataAbort {
+        origin: Origin,
+        status: FaultStatus,
+        write: bool,
+    },
+    PcAlignment,
+    SpAlignment,
+    SError,
+    Brk {
+        imm: u16,
+    },
+    Other {
+        ec: u8,
+        iss: u32,
+    },
+}
+
+impl ExceptionKind {
+    pub fn decode(esr: u64) -> Self {
+        let ec = esr >> EC_SHIFT & EC_MASK;
+        let iss = esr & ISS_MASK;
+        match ec {
+            EC_UNKNOWN => ExceptionKind::UndefinedInstruction,
+            EC_TRAPPED_WFI_WFE => ExceptionKind::TrappedWfiWfe,
+            EC_TRAPPED_FP => ExceptionKind::TrappedFp,
+            EC_ILLEGAL_EXECUTION_STATE => ExceptionKind::IllegalExecutionState,
+            EC_SVC64 => ExceptionKind::Svc { imm: iss as u16 },
+            EC_TRAPPED_MSR_MRS => ExceptionKind::TrappedSysReg,
+            EC_INSTR_ABORT_LOWER_EL | EC_INSTR_ABORT_CURRENT_EL => {
+                ExceptionKind::InstructionAbort {
+                    origin: Origin::from_abort_class(ec),
+                    status: FaultStatus::decode(iss & ISS_FSC_MASK),
+                }
+            }
+            EC_DATA_ABORT_LOWER_EL | EC_DATA_ABORT_CURRENT_EL => ExceptionKind::DataAbort {
+                origin: Origin::from_abort_class(ec),
+                status: FaultStatus::decode(iss & ISS_FSC_MASK),
+                write: iss & ISS_WNR != 0,
+            },
+            EC_PC_ALIGNMENT_FAULT => ExceptionKind::PcAlignment,
+            EC_SP_ALIGNMENT_FAULT => ExceptionKind::SpAlignment,
+            EC_SERROR => ExceptionKind::SError,
+            EC_BRK64 => ExceptionKind::Brk { imm: iss as u16 },
+            _ => ExceptionKind::Other {
+                ec: ec as u8,
+                iss: iss as u32,
+            },
+        }
+    }
+}
+
+impl Origin {
+    /// The lower and current EL variants of abort classes differ in the lowest bit.
+    fn from_abort_class(ec: u64) -> Self {
+        if ec & 1 == 0 {
+            Origin::LowerEl
+        } else {
+            Origin::CurrentEl
+        }
+    }
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Origin::CurrentEl => write!(f, "current EL"),
+            Origin::LowerEl => write!(f, "lower EL"),
+        }
+    }
+}
+
+impl fmt::Display for FaultStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FaultStatus::AddressSize { level } => write!(f, "address size fault, level {}", level),
+            FaultStatus::Translation { level } => write!(f, "translation fault, level {}", level),
+            FaultStatus::AccessFlag { level } => write!(f, "access flag fault, level {}", level),
+            FaultStatus::Permission { level } => write!(f, "permission fault, level {}", level),
+            FaultStatus::SynchronousExternal => write!(f, "synchronous external abort"),
+            FaultStatus::Alignment => write!(f, "alignment fault"),
+            FaultStatus::TlbConflict => write!(f, "TLB conflict abort"),
+            FaultStatus::Other(fsc) => write!(f, "fault status {:#04x}", fsc),
+        }
+    }
+}
+
+impl fmt::Display for ExceptionKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExceptionKind::UndefinedInstruction => write!(f, "Undefined instruction"),
+            ExceptionKind::TrappedWfiWfe => write!(f, "Trapped WFI or WFE"),
+            ExceptionKind::TrappedFp => write!(f, "Trapped SIMD or floating point access"),
+            ExceptionKind::IllegalExecutionState => write!(f, "Illegal execution state"),
+            ExceptionKind::Svc { imm } => write!(f, "SVC #{:#x}", imm),
+            ExceptionKind::TrappedSysReg => write!(f, "Trapped system register access"),
+            ExceptionKind::InstructionAbort { origin, status } => {
+                write!(f, "Instruction abort from {}: {}", origin, status)
+            }
+            ExceptionKind::DataAbort {
+                origin,
+                status,
+                write,
+            } => write!(
+                f,
+                "Data abort from {} on {}: {}",
+                origin,
+                if *write { "write" } else { "read" },
+                status
+            ),
+            ExceptionKind::PcAlignment => write!(f, "PC alignment fault"),
+            ExceptionKind::SpAlignment => write!(f, "SP alignment fault"),
+            ExceptionKind::SError => write!(f, "SError interrupt"),
+            ExceptionKind::Brk { imm } => write!(f, "BRK #{:#x}", imm),
+            ExceptionKind::Other { ec, iss } => {
+                write!(f, "Exception class {:#04x}, ISS {:#x}", ec, iss)
+            }
+        }
+    }
+}
@@ -1,16 +1,27 @@
-use core::{cell::UnsafeCell, fmt};
+use alloc::vec::Vec;
+use core::{cell::UnsafeCell, fmt, ops::Range};
 use cortex_a::{asm, registers::*};
 use tock_registers::{interfaces::*, registers::InMemoryRegister};
 
+use super::{esr::ExceptionKind, irq, user};
+use crate::{println, sync::IrqSpinLock};
+
 core::arch::global_asm!(include_str!("exception.s"));
 
 /// Wrapper struct for memory copy of SPSR_EL1.
 #[repr(transparent)]
 struct SpsrEL1(InMemoryRegister<u64, SPSR_EL1::Register>);
 
+/// Wrapper struct for memory copy of ESR_EL1.
+#[repr(transparent)]
+struct EsrEL1(InMemoryRegister<u64, ESR_EL1::Register>);
+
 /// The exception context as it is stored on the stack on exception entry.
+///
+/// For exceptions taken from EL0, this is the user thread's trap frame: it is restored on the way
+/// back, so changes to it, like a system call result in x0, show up in user space.
 #[repr(C)]
-struct ExceptionContext {
+pub struct ExceptionContext {
     /// General Purpose Registers.
     gpr: [u64; 30],
 
@@ -22,36 +33,228 @@ struct ExceptionContext {
 
     /// Saved program status.
     spsr_el1: SpsrEL1,
+
+    /// Exception syndrome register.
+    esr_el1: EsrEL1,
+
+    /// The user stack pointer.
+    sp_el0: u64,
+
+    /// Keeps the stack 16 byte aligned.
+    _reserved: u64,
+}
+
+impl ExceptionContext {
+    /// A context that returns to EL0 at `entry` with `sp` and `arg` in x0, and all exceptions
+    /// unmasked.
+    pub(super) fn new_user(entry: usize, sp: usize, arg: usize) -> Self {
+        let mut gpr = [0; 30];
+        gpr[0] = arg as u64;
+        Self {
+            gpr,
+            lr: 0,
+            elr_el1: entry as u64,
+            // EL0t, DAIF clear.
+            spsr_el1: SpsrEL1(InMemoryRegister::new(0)),
+            esr_el1: EsrEL1(InMemoryRegister::new(0)),
+            sp_el0: sp as u64,
+            _reserved: 0,
+        }
+    }
 }
 
-/// Wrapper struct for pretty printing ESR_EL1.
-struct EsrEL1;
+/// Exit code of a user thread killed because of an exception.
+const USER_FAULT_EXIT_CODE: i32 = -1;
+
+/// A code range whose data aborts are recovered from by resuming at `fixup`.
+struct FaultFixup {
+    code: Range<usize>,
+    fixup: usize,
+}
+
+static FAULT_FIXUPS: IrqSpinLock<Vec<FaultFixup>> = IrqSpinLock::new(Vec::new());
+
+/// Makes a data abort taken at EL1 with the faulting instruction in `code` resume at `fixup`
+/// instead of crashing.
+///
+/// This lets code that touches memory it does not trust, like copying from user space, report an
+/// error instead of bringing down the kernel.
+pub fn register_fault_fixup(code: Range<usize>, fixup: usize) {
+    FAULT_FIXUPS.lock().push(FaultFixup { code, fixup });
+}
+
+fn search_fault_fixup(pc: usize) -> Option<usize> {
+    FAULT_FIXUPS
+        .lock()
+        .iter()
+        .find(|entry| entry.code.contains(&pc))
+        .map(|entry| entry.fixup)
+}
 
 /// Prints verbose information about the exception and then panics.
-fn default_exception_handler(e: &ExceptionContext) {
+///
+/// `vector` names the vector table entry that was taken.
+fn default_exception_handler(e: &ExceptionContext, vector: &str) {
     crate::print!(
-        "\n\nCPU Exception!\n\
+        "\n\nCPU Exception: {}!\n\
          FAR_EL1: {:#018x}\n\
+         {}\n\
          {}",
+        vector,
         FAR_EL1.get(),
+        e.esr_el1,
         e
     );
     panic!()
 }
 
+//--------------------------------------------------------------------------------------------------
+// Current, EL0
+//--------------------------------------------------------------------------------------------------
+
+// The kernel always runs on SP_EL1, so none of these should ever be taken.
+
+#[no_mangle]
+unsafe extern "C" fn current_el0_synchronous(e: &mut ExceptionContext) {
+    default_exception_handler(e, "synchronous, current EL with SP_EL0");
+}
+
+#[no_mangle]
+unsafe extern "C" fn current_el0_irq(e: &mut ExceptionContext) {
+    default_exception_handler(e, "IRQ, current EL with SP_EL0");
+}
+
+#[no_mangle]
+unsafe extern "C" fn current_el0_fiq(e: &mut ExceptionContext) {
+    default_exception_handler(e, "FIQ, current EL with SP_EL0");
+}
+
+#[no_mangle]
+unsafe extern "C" fn current_el0_serror(e: &mut ExceptionContext) {
+    default_exception_handler(e, "SError, current EL with SP_EL0");
+}
+
+//--------------------------------------------------------------------------------------------------
+// Current, ELx
+//--------------------------------------------------------------------------------------------------
+
 #[no_mangle]
 unsafe extern "C" fn current_elx_synchronous(e: &mut ExceptionContext) {
-    let far_el1 = FAR_EL1.get();
+    if let ExceptionKind::DataAbort { .. } = e.esr_el1.kind() {
+        if let Some(fixup) = search_fault_fixup(e.elr_el1 as usize) {
+            e.elr_el1 = fixup as u64;
+            return;
+        }
+    }
+
+    default_exception_handler(e, "synchronous, current EL");
+}
+
+#[no_mangle]
+unsafe extern "C" fn current_elx_irq(_e: &mut ExceptionContext) {
+    irq::handle_irq();
+    crate::thread::preempt_if_needed();
+}
 
-    // This catches the demo case for this tutorial. If the fault address happens to be 8 GiB,
-    // advance the exception link register for one instruction, so that execution can continue.
-    if far_el1 == 8 * 1024 * 1024 * 1024 {
-        e.elr_el1 += 4;
+#[no_mangle]
+unsafe extern "C" fn current_elx_fiq(e: &mut ExceptionContext) {
+    default_exception_handler(e, "FIQ, current EL");
+}
+
+#[no_mangle]
+unsafe extern "C" fn current_elx_serror(e: &mut ExceptionContext) {
+    default_exception_handler(e, "SError, current EL");
+}
+
+//--------------------------------------------------------------------------------------------------
+// Lower, AArch64
+//--------------------------------------------------------------------------------------------------
+
+#[no_mangle]
+unsafe extern "C" fn lower_aarch64_synchronous(e: &mut ExceptionContext) {
+    match e.esr_el1.kind() {
+        ExceptionKind::Svc { .. } => {
+            // System calls may block, so run them like the thread that made them.
+            irq::local_irq_enable();
+            let args = core::array::from_fn(|i| e.gpr[i] as usize);
+            e.gpr[0] = crate::syscall::dispatch(e.gpr[8] as usize, &args) as u64;
+            irq::local_irq_disable();
+        }
+        kind => {
+            println!(
+                "User thread killed: {} at {:#x}, FAR_EL1 {:#x}",
+                kind,
+                e.elr_el1,
+                FAR_EL1.get()
+            );
+            user::exit_user(USER_FAULT_EXIT_CODE);
+        }
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn lower_aarch64_irq(_e: &mut ExceptionContext) {
+    irq::handle_irq();
+    crate::thread::preempt_if_needed();
+}
+
+#[no_mangle]
+unsafe extern "C" fn lower_aarch64_fiq(e: &mut ExceptionContext) {
+    default_exception_handler(e, "FIQ, lower EL AArch64");
+}
+
+#[no_mangle]
+unsafe extern "C" fn lower_aarch64_serror(e: &mut ExceptionContext) {
+    default_exception_handler(e, "SError, lower EL AArch64");
+}
 
-        asm::eret()
+//--------------------------------------------------------------------------------------------------
+// Lower, AArch32
+//--------------------------------------------------------------------------------------------------
+
+// AArch32 is never enabled for EL0.
+
+#[no_mangle]
+unsafe extern "C" fn lower_aarch32_synchronous(e: &mut ExceptionContext) {
+    default_exception_handler(e, "synchronous, lower EL AArch32");
+}
+
+#[no_mangle]
+unsafe extern "C" fn lower_aarch32_irq(e: &mut ExceptionContext) {
+    default_exception_handler(e, "IRQ, lower EL AArch32");
+}
+
+#[no_mangle]
+unsafe extern "C" fn lower_aarch32_fiq(e: &mut ExceptionContext) {
+    default_exception_handler(e, "FIQ, lower EL AArch32");
+}
+
+#[no_mangle]
+unsafe extern "C" fn lower_aarch32_serror(e: &mut ExceptionContext) {
+    default_exception_handler(e, "SError, lower EL AArch32");
+}
+
+//--------------------------------------------------------------------------------------------------
+// Pretty printing
+//--------------------------------------------------------------------------------------------------
+
+impl EsrEL1 {
+    fn kind(&self) -> ExceptionKind {
+        ExceptionKind::decode(self.0.get())
     }
+}
 
-    default_exception_handler(e);
+/// Human readable ESR_EL1.
+#[rustfmt::skip]
+impl fmt::Display for EsrEL1 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Raw value.
+        writeln!(f, "ESR_EL1: {:#010x}", self.0.get())?;
+
+        writeln!(f, "      Exception Class         (EC) : {:#x}", self.0.read(ESR_EL1::EC))?;
+        writeln!(f, "      Instr Specific Syndrome (ISS): {:#x}", self.0.read(ESR_EL1::ISS))?;
+        write!(f, "      {}", self.kind())
+    }
 }
 
 /// Human readable SPSR_EL1.
@@ -93,6 +296,7 @@ impl fmt::Display for SpsrEL1 {
 impl fmt::Display for ExceptionContext {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "ELR_EL1: {:#018x}", self.elr_el1)?;
+        writeln!(f, "SP_EL0: {:#018x}", self.sp_el0)?;
         writeln!(f, "{}", self.spsr_el1)?;
         writeln!(f)?;
         writeln!(f, "General purpose register:")?;
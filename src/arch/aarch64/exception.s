@@ -2,7 +2,7 @@
 /// the context as the first parameter to '\handler'.
 .macro CALL_WITH_CONTEXT handler
     // Make room on the stack for the exception context.
-    sub    sp,  sp,  #16 * 17
+    sub    sp,  sp,  #16 * 18
 
     // Store all general purpose registers on the stack.
     stp    x0,  x1,  [sp, #16 * 0]
@@ -21,12 +21,16 @@
     stp    x26, x27, [sp, #16 * 13]
     stp    x28, x29, [sp, #16 * 14]
 
-    // Add the exception link register (ELR_EL1) and the saved program status (SPSR_EL1).
+    // Add the exception link register (ELR_EL1), the saved program status (SPSR_EL1), the
+    // exception syndrome (ESR_EL1) and the user stack pointer (SP_EL0).
     mrs    x1,  ELR_EL1
     mrs    x2,  SPSR_EL1
+    mrs    x3,  ESR_EL1
+    mrs    x4,  SP_EL0
 
     stp    lr,  x1,  [sp, #16 * 15]
-    str    x2,       [sp, #16 * 16]
+    stp    x2,  x3,  [sp, #16 * 16]
+    str    x4,       [sp, #16 * 17]
 
     // x0 is the first argument for the function called through `\handler`.
     mov    x0,  sp
@@ -49,17 +53,60 @@
 // Export a symbol for the Rust code to use.
 __EXCEPTION_VECTOR_START:
 
+// Current exception level with SP_EL0.
+.org 0x000
+    CALL_WITH_CONTEXT current_el0_synchronous
+.org 0x080
+    CALL_WITH_CONTEXT current_el0_irq
+.org 0x100
+    CALL_WITH_CONTEXT current_el0_fiq
+.org 0x180
+    CALL_WITH_CONTEXT current_el0_serror
+
+// Current exception level with SP_ELx, x > 0.
 .org 0x200
     CALL_WITH_CONTEXT current_elx_synchronous
+.org 0x280
+    CALL_WITH_CONTEXT current_elx_irq
+.org 0x300
+    CALL_WITH_CONTEXT current_elx_fiq
+.org 0x380
+    CALL_WITH_CONTEXT current_elx_serror
+
+// Lower exception level, AArch64.
+.org 0x400
+    CALL_WITH_CONTEXT lower_aarch64_synchronous
+.org 0x480
+    CALL_WITH_CONTEXT lower_aarch64_irq
+.org 0x500
+    CALL_WITH_CONTEXT lower_aarch64_fiq
+.org 0x580
+    CALL_WITH_CONTEXT lower_aarch64_serror
+
+// Lower exception level, AArch32.
+.org 0x600
+    CALL_WITH_CONTEXT lower_aarch32_synchronous
+.org 0x680
+    CALL_WITH_CONTEXT lower_aarch32_irq
+.org 0x700
+    CALL_WITH_CONTEXT lower_aarch32_fiq
+.org 0x780
+    CALL_WITH_CONTEXT lower_aarch32_serror
+.org 0x800
 
     .section .text
 
+// Returns from an exception with the context at sp. Also used by `__enter_user` in user.s to drop
+// to EL0 for the first time.
+.globl __exception_restore_context
 __exception_restore_context:
     ldr    w19,      [sp, #16 * 16]
     ldp    lr,  x20, [sp, #16 * 15]
+    ldr    x21,      [sp, #16 * 17]
 
     msr    SPSR_EL1, x19
     msr    ELR_EL1,  x20
+    msr    SP_EL0,   x21
 
     ldp    x0,  x1,  [sp, #16 * 0]
     ldp    x2,  x3,  [sp, #16 * 1]
@@ -77,6 +124,6 @@ __exception_restore_context:
     ldp    x26, x27, [sp, #16 * 13]
     ldp    x28, x29, [sp, #16 * 14]
 
-    add    sp,  sp,  #16 * 17
+    add    sp,  sp,  #16 * 18
 
     eret
@@ -1,6 +1,7 @@
 use core::{convert::TryInto, mem, slice, str};
 
 use crate::{
+    arch::mmu::phys_to_virt,
     println,
     utils::{align_to, to_cstr, PointerExt as _, BE},
 };
@@ -27,7 +28,7 @@ static mut __EXT_FDT_PTR: u64 = 0;
 
 pub unsafe fn fdt_init() {
     unsafe {
-        FDT = Some(&*(__EXT_FDT_PTR as *const FdtHeader));
+        FDT = Some(&*(phys_to_virt(__EXT_FDT_PTR as usize) as *const FdtHeader));
     }
     assert_eq!(u32::from(fdt_header().magic), 0xd00dfeed);
     println!("Initialized FDT.");
@@ -60,6 +61,7 @@ fn fdt_get_string(offset: u32) -> &'static [u8] {
     }
 }
 
+#[derive(Clone)]
 struct FdtStructIter {
     ptr: *const BE<u32>,
     end: *const BE<u32>,
@@ -128,7 +130,213 @@ fn fdt_struct_iter() -> FdtStructIter {
     FdtStructIter { ptr: start, end }
 }
 
-//pub fn
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[0..4].try_into().unwrap())
+}
+
+/// Reads a value made of `cells` big endian u32 cells from the start of `bytes`.
+fn read_cells(bytes: &[u8], cells: u32) -> u64 {
+    (0..cells as usize).fold(0, |acc, i| (acc << 32) | be_u32(&bytes[i * 4..]) as u64)
+}
+
+/// A node of the device tree.
+#[derive(Clone)]
+pub struct FdtNode {
+    pub name: &'static [u8],
+    /// Positioned right after the node's `FdtBeginNode`, i.e. at its first property.
+    props: FdtStructIter,
+    /// `#address-cells` and `#size-cells` of the parent node, which describe this node's `reg`.
+    address_cells: u32,
+    size_cells: u32,
+}
+
+impl FdtNode {
+    pub fn property(&self, name: &str) -> Option<&'static [u8]> {
+        use FdtStructEntry::*;
+        let mut iter = self.props.clone();
+        while let Some(entry) = iter.next() {
+            match entry {
+                FdtProp { name: prop_name, value, .. } if prop_name == name.as_bytes() => {
+                    return Some(value)
+                }
+                FdtProp { .. } | FdtNop => {}
+                _ => break,
+            }
+        }
+        None
+    }
+
+    /// Returns the `index`-th u32 cell of a property.
+    pub fn property_cell(&self, name: &str, index: usize) -> Option<u32> {
+        self.property(name)?.get(index * 4..(index + 1) * 4).map(be_u32)
+    }
+
+    pub fn has_property(&self, name: &str) -> bool {
+        self.property(name).is_some()
+    }
+
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.property("compatible").is_some_and(|value| {
+            value
+                .split(|&b| b == 0)
+                .any(|s| s == compatible.as_bytes())
+        })
+    }
+
+    /// Returns the `n`-th `(address, size)` pair of the node's `reg` property.
+    pub fn reg(&self, n: usize) -> Option<(u64, u64)> {
+        let value = self.property("reg")?;
+        let entry_len = (self.address_cells + self.size_cells) as usize * 4;
+        let entry = value.get(n * entry_len..(n + 1) * entry_len)?;
+        let addr = read_cells(entry, self.address_cells);
+        let size = read_cells(&entry[self.address_cells as usize * 4..], self.size_cells);
+        Some((addr, size))
+    }
+}
+
+const FDT_MAX_DEPTH: usize = 16;
+
+/// Iterates over all nodes of the device tree in depth-first order.
+pub struct FdtNodeIter {
+    iter: FdtStructIter,
+    depth: usize,
+    /// `(#address-cells, #size-cells)` declared by the open node at each depth.
+    cells: [(u32, u32); FDT_MAX_DEPTH],
+}
+
+impl Iterator for FdtNodeIter {
+    type Item = FdtNode;
+
+    fn next(&mut self) -> Option<FdtNode> {
+        use FdtStructEntry::*;
+        while let Some(entry) = self.iter.next() {
+            match entry {
+                FdtBeginNode { name } => {
+                    let (address_cells, size_cells) = self.cells[self.depth];
+                    self.depth += 1;
+                    assert!(self.depth < FDT_MAX_DEPTH, "FDT nested too deeply");
+                    self.cells[self.depth] = (2, 1);
+
+                    return Some(FdtNode {
+                        name,
+                        props: self.iter.clone(),
+                        address_cells,
+                        size_cells,
+                    });
+                }
+                FdtProp { name: b"#address-cells", value, .. } => {
+                    self.cells[self.depth].0 = be_u32(value);
+                }
+                FdtProp { name: b"#size-cells", value, .. } => {
+                    self.cells[self.depth].1 = be_u32(value);
+                }
+                FdtEndNode => self.depth -= 1,
+                FdtEnd => return None,
+                FdtProp { .. } | FdtNop => {}
+            }
+        }
+        None
+    }
+}
+
+pub fn fdt_nodes() -> FdtNodeIter {
+    FdtNodeIter {
+        iter: fdt_struct_iter(),
+        depth: 0,
+        cells: [(2, 1); FDT_MAX_DEPTH],
+    }
+}
+
+/// Returns the physical `[start, end)` of the initial RAM disk, as passed by the bootloader in
+/// `/chosen`.
+pub fn fdt_get_initrd() -> Option<(usize, usize)> {
+    let chosen = fdt_nodes().find(|node| node.name == b"chosen")?;
+    // Both properties are either one or two cells long.
+    let read = |name| match chosen.property(name)? {
+        value if value.len() == 4 || value.len() == 8 => {
+            Some(read_cells(value, value.len() as u32 / 4) as usize)
+        }
+        _ => None,
+    };
+    let start = read("linux,initrd-start")?;
+    let end = read("linux,initrd-end")?;
+    (start < end).then_some((start, end))
+}
+
+/// Returns the physical `[start, end)` of the device tree blob itself.
+pub fn fdt_get_blob() -> (usize, usize) {
+    let start = unsafe { __EXT_FDT_PTR } as usize;
+    (start, start + u32::from(fdt_header().totalsize) as usize)
+}
+
+/// Formats a property value the way device tree sources write it.
+#[cfg(feature = "debug_shell")]
+struct PropertyValue(&'static [u8]);
+
+#[cfg(feature = "debug_shell")]
+impl core::fmt::Display for PropertyValue {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let value = self.0;
+        let is_strings = value.last() == Some(&0)
+            && value[..value.len() - 1]
+                .split(|&b| b == 0)
+                .all(|s| !s.is_empty() && s.iter().all(|b| b.is_ascii_graphic() || *b == b' '));
+        if value.is_empty() {
+            Ok(())
+        } else if is_strings {
+            let strings = value[..value.len() - 1].split(|&b| b == 0);
+            for (i, s) in strings.enumerate() {
+                let separator = if i == 0 { " = " } else { ", " };
+                write!(f, "{}\"{}\"", separator, str::from_utf8(s).unwrap())?;
+            }
+            Ok(())
+        } else if value.len() % 4 == 0 {
+            write!(f, " = <")?;
+            for (i, cell) in value.chunks(4).enumerate() {
+                let separator = if i == 0 { "" } else { " " };
+                write!(f, "{}{:#x}", separator, be_u32(cell))?;
+            }
+            write!(f, ">")
+        } else {
+            write!(f, " = [")?;
+            for (i, byte) in value.iter().enumerate() {
+                let separator = if i == 0 { "" } else { " " };
+                write!(f, "{}{:02x}", separator, byte)?;
+            }
+            write!(f, "]")
+        }
+    }
+}
+
+/// Prints the whole device tree in source form, for debugging.
+#[cfg(feature = "debug_shell")]
+pub fn fdt_print() {
+    use FdtStructEntry::*;
+    let mut depth = 0;
+    for entry in fdt_struct_iter() {
+        let indent = depth * 4;
+        match entry {
+            FdtBeginNode { name } => {
+                let name = match name {
+                    b"" => "/",
+                    _ => str::from_utf8(name).unwrap_or("?"),
+                };
+                println!("{:indent$}{} {{", "", name);
+                depth += 1;
+            }
+            FdtEndNode => {
+                depth -= 1;
+                println!("{:indent$}}};", "", indent = indent - 4);
+            }
+            FdtProp { name, value, .. } => {
+                let name = str::from_utf8(name).unwrap_or("?");
+                println!("{:indent$}{}{};", "", name, PropertyValue(value));
+            }
+            FdtNop => {}
+            FdtEnd => break,
+        }
+    }
+}
 
 pub fn get_memory_size() -> usize {
     0
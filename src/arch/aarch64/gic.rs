@@ -0,0 +1,412 @@
+//! ARM Generic Interrupt Controller driver.
+//!
+//! Supports GICv2 (QEMU virt default, Allwinner A64) through the memory mapped CPU interface and
+//! GICv3 (QEMU with `gic-version=3`) through the system register CPU interface. All interrupts are
+//! configured as Group 1, so they are signaled as IRQs.
+
+use core::{
+    ptr,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use cortex_a::{asm::barrier, registers::MPIDR_EL1};
+use tock_registers::interfaces::*;
+
+use super::{
+    fdt::{self, FdtNode},
+    mmu::{self, phys_to_virt},
+};
+use crate::{
+    cpu::{self, Cpu, MAX_CPUS},
+    println,
+};
+
+/// Interrupt IDs 1020-1023 are special; 1023 means there is no pending interrupt.
+pub const SPURIOUS_IRQ: u32 = 1023;
+
+/// Number of interrupt IDs the dispatch table has to cover: SGIs, PPIs and SPIs.
+pub const MAX_IRQS: usize = 1020;
+
+const GICV2_COMPATIBLE: &[&str] = &[
+    "arm,gic-400",
+    "arm,cortex-a15-gic",
+    "arm,cortex-a9-gic",
+    "arm,cortex-a7-gic",
+];
+const GICV3_COMPATIBLE: &[&str] = &["arm,gic-v3"];
+
+const DEFAULT_PRIORITY: u8 = 0xa0;
+
+// Distributor registers, common to GICv2 and GICv3.
+const GICD_CTLR: usize = 0x000;
+const GICD_TYPER: usize = 0x004;
+const GICD_IGROUPR: usize = 0x080;
+const GICD_ISENABLER: usize = 0x100;
+const GICD_ICENABLER: usize = 0x180;
+const GICD_ICPENDR: usize = 0x280;
+const GICD_IPRIORITYR: usize = 0x400;
+const GICD_ITARGETSR: usize = 0x800;
+const GICD_SGIR: usize = 0xf00;
+const GICD_IROUTER: usize = 0x6000;
+
+const GICD_CTLR_ENABLE_GRP0: u32 = 1 << 0;
+const GICD_CTLR_ENABLE_GRP1: u32 = 1 << 1;
+const GICD_CTLR_ARE: u32 = 1 << 4;
+const GICD_CTLR_RWP: u32 = 1 << 31;
+
+// GICv2 CPU interface registers.
+const GICC_CTLR: usize = 0x00;
+const GICC_PMR: usize = 0x04;
+const GICC_BPR: usize = 0x08;
+const GICC_IAR: usize = 0x0c;
+const GICC_EOIR: usize = 0x10;
+
+// GICv3 redistributor registers. Each CPU has an RD frame followed by an SGI frame.
+const GICR_FRAME_STRIDE: usize = 0x20000;
+const GICR_SGI_OFFSET: usize = 0x10000;
+const GICR_TYPER: usize = 0x008;
+const GICR_WAKER: usize = 0x014;
+const GICR_TYPER_LAST: u64 = 1 << 4;
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+
+/// A memory mapped register block.
+#[derive(Clone, Copy)]
+struct Mmio {
+    base: usize,
+}
+
+impl Mmio {
+    fn new(phys: u64) -> Self {
+        Self {
+            base: phys_to_virt(phys as usize),
+        }
+    }
+
+    fn offset(self, offset: usize) -> Self {
+        Self {
+            base: self.base + offset,
+        }
+    }
+
+    fn read(self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.base + offset) as *const u32) }
+    }
+
+    fn write(self, offset: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.base + offset) as *mut u32, value) }
+    }
+
+    fn read64(self, offset: usize) -> u64 {
+        unsafe { ptr::read_volatile((self.base + offset) as *const u64) }
+    }
+
+    fn write64(self, offset: usize, value: u64) {
+        unsafe { ptr::write_volatile((self.base + offset) as *mut u64, value) }
+    }
+
+    fn write8(self, offset: usize, value: u8) {
+        unsafe { ptr::write_volatile((self.base + offset) as *mut u8, value) }
+    }
+
+    /// Sets the bit for `irq` in a bank of one-bit-per-interrupt registers.
+    fn set_irq_bit(self, bank: usize, irq: u32) {
+        self.write(bank + (irq as usize / 32) * 4, 1 << (irq % 32));
+    }
+}
+
+/// GICv3 CPU interface system registers, accessed by encoding so that no GIC support is needed
+/// from the assembler.
+mod icc {
+    macro_rules! sysreg {
+        ($name:ident, $encoding:literal) => {
+            pub mod $name {
+                pub fn read() -> u64 {
+                    let value: u64;
+                    unsafe {
+                        core::arch::asm!(concat!("mrs {}, ", $encoding), out(reg) value, options(nostack))
+                    };
+                    value
+                }
+
+                pub fn write(value: u64) {
+                    unsafe {
+                        core::arch::asm!(concat!("msr ", $encoding, ", {}"), in(reg) value, options(nostack))
+                    };
+                }
+            }
+        };
+    }
+
+    sysreg!(iar1_el1, "S3_0_C12_C12_0");
+    sysreg!(eoir1_el1, "S3_0_C12_C12_1");
+    sysreg!(bpr1_el1, "S3_0_C12_C12_3");
+    sysreg!(sre_el1, "S3_0_C12_C12_5");
+    sysreg!(igrpen1_el1, "S3_0_C12_C12_7");
+    sysreg!(pmr_el1, "S3_0_C4_C6_0");
+    sysreg!(sgi1r_el1, "S3_0_C12_C11_5");
+}
+
+/// The affinity of the executing core in the format of GICR_TYPER[63:32] and GICD_IROUTER.
+fn mpidr_affinity() -> u64 {
+    let mpidr = MPIDR_EL1.get();
+    (mpidr & 0xff_ffff) | ((mpidr >> 32 & 0xff) << 24)
+}
+
+pub struct GicV2 {
+    gicd: Mmio,
+    gicc: Mmio,
+    /// GICD_ITARGETSR bit of each CPU's interface, by CPU index. Used as SGI target list.
+    cpu_masks: [AtomicU8; MAX_CPUS],
+}
+
+pub struct GicV3 {
+    gicd: Mmio,
+    gicr: Mmio,
+}
+
+pub enum Gic {
+    V2(GicV2),
+    V3(GicV3),
+}
+
+/// Returns the `n`-th register frame of the GIC, or `None` if the kernel does not map it.
+fn reg_mmio(node: &FdtNode, n: usize) -> Option<Mmio> {
+    let (addr, size) = node
+        .reg(n)
+        .expect("interrupt controller node has no `reg` entry");
+    if !mmu::is_device_mapped(addr as usize, size as usize) {
+        println!(
+            "GIC: registers at {:#x}..{:#x} are outside of the mapped device memory",
+            addr,
+            addr + size
+        );
+        return None;
+    }
+    Some(Mmio::new(addr))
+}
+
+impl Gic {
+    /// Finds the GIC in the device tree. Other interrupt controllers, such as GPIO controllers,
+    /// are skipped. Returns `None` if there is none or its registers are not mapped.
+    pub fn probe() -> Option<Gic> {
+        let is_gic = |node: &FdtNode| {
+            GICV2_COMPATIBLE
+                .iter()
+                .chain(GICV3_COMPATIBLE)
+                .any(|c| node.is_compatible(c))
+        };
+        let node = fdt::fdt_nodes()
+            .find(|node| node.has_property("interrupt-controller") && is_gic(node))?;
+        if GICV2_COMPATIBLE.iter().any(|c| node.is_compatible(c)) {
+            Some(Gic::V2(GicV2 {
+                gicd: reg_mmio(&node, 0)?,
+                gicc: reg_mmio(&node, 1)?,
+                cpu_masks: Default::default(),
+            }))
+        } else if GICV3_COMPATIBLE.iter().any(|c| node.is_compatible(c)) {
+            Some(Gic::V3(GicV3 {
+                gicd: reg_mmio(&node, 0)?,
+                gicr: reg_mmio(&node, 1)?,
+            }))
+        } else {
+            None
+        }
+    }
+
+    pub fn version(&self) -> u32 {
+        match self {
+            Gic::V2(_) => 2,
+            Gic::V3(_) => 3,
+        }
+    }
+
+    fn gicd(&self) -> Mmio {
+        match self {
+            Gic::V2(gic) => gic.gicd,
+            Gic::V3(gic) => gic.gicd,
+        }
+    }
+
+    fn num_irqs(&self) -> u32 {
+        let lines = ((self.gicd().read(GICD_TYPER) & 0x1f) + 1) * 32;
+        lines.min(MAX_IRQS as u32)
+    }
+
+    /// Initializes the distributor: all SPIs disabled, Group 1 and at the default priority.
+    ///
+    /// Must be called once, before `init_cpu` on any core.
+    pub fn init_distributor(&self) {
+        let gicd = self.gicd();
+        gicd.write(GICD_CTLR, 0);
+        self.wait_for_rwp();
+
+        for irq in (32..self.num_irqs()).step_by(32) {
+            let bank = (irq / 32) as usize * 4;
+            gicd.write(GICD_ICENABLER + bank, !0);
+            gicd.write(GICD_ICPENDR + bank, !0);
+            gicd.write(GICD_IGROUPR + bank, !0);
+        }
+        for irq in 32..self.num_irqs() {
+            gicd.write8(GICD_IPRIORITYR + irq as usize, DEFAULT_PRIORITY);
+        }
+
+        let ctlr = match self {
+            Gic::V2(_) => GICD_CTLR_ENABLE_GRP0 | GICD_CTLR_ENABLE_GRP1,
+            Gic::V3(_) => GICD_CTLR_ENABLE_GRP0 | GICD_CTLR_ENABLE_GRP1 | GICD_CTLR_ARE,
+        };
+        gicd.write(GICD_CTLR, ctlr);
+        self.wait_for_rwp();
+    }
+
+    /// Initializes the executing core's CPU interface (and redistributor for GICv3).
+    pub fn init_cpu(&self) {
+        match self {
+            Gic::V2(gic) => {
+                // Reading any of the first eight ITARGETSR bytes returns the own CPU mask.
+                let own = gic.gicd.read(GICD_ITARGETSR) as u8;
+                gic.cpu_masks[cpu::current().id()].store(own, Ordering::Relaxed);
+
+                // SGIs and PPIs are banked per core in the distributor.
+                gic.gicd.write(GICD_ICENABLER, !0);
+                gic.gicd.write(GICD_IGROUPR, !0);
+                for irq in 0..32 {
+                    gic.gicd.write8(GICD_IPRIORITYR + irq, DEFAULT_PRIORITY);
+                }
+
+                gic.gicc.write(GICC_PMR, 0xff);
+                gic.gicc.write(GICC_BPR, 0);
+                gic.gicc.write(GICC_CTLR, 0b11);
+            }
+            Gic::V3(gic) => {
+                let rd = gic.redistributor();
+                rd.write(GICR_WAKER, rd.read(GICR_WAKER) & !GICR_WAKER_PROCESSOR_SLEEP);
+                while rd.read(GICR_WAKER) & GICR_WAKER_CHILDREN_ASLEEP != 0 {}
+
+                let sgi = rd.offset(GICR_SGI_OFFSET);
+                sgi.write(GICD_ICENABLER, !0);
+                sgi.write(GICD_IGROUPR, !0);
+                for irq in 0..32 {
+                    sgi.write8(GICD_IPRIORITYR + irq, DEFAULT_PRIORITY);
+                }
+
+                icc::sre_el1::write(icc::sre_el1::read() | 1);
+                barrier::isb(barrier::SY);
+                icc::pmr_el1::write(0xff);
+                icc::bpr1_el1::write(0);
+                icc::igrpen1_el1::write(1);
+                barrier::isb(barrier::SY);
+            }
+        }
+    }
+
+    /// Enables `irq`. SPIs are routed to the executing core.
+    pub fn enable(&self, irq: u32) {
+        match self {
+            Gic::V2(gic) => {
+                if irq >= 32 {
+                    // Reading any of the first eight ITARGETSR bytes returns the own CPU mask.
+                    let own = gic.gicd.read(GICD_ITARGETSR) as u8;
+                    gic.gicd.write8(GICD_ITARGETSR + irq as usize, own);
+                }
+                gic.gicd.set_irq_bit(GICD_ISENABLER, irq);
+            }
+            Gic::V3(gic) => {
+                if irq < 32 {
+                    let sgi = gic.redistributor().offset(GICR_SGI_OFFSET);
+                    sgi.set_irq_bit(GICD_ISENABLER, irq);
+                } else {
+                    gic.gicd
+                        .write64(GICD_IROUTER + irq as usize * 8, mpidr_affinity());
+                    gic.gicd.set_irq_bit(GICD_ISENABLER, irq);
+                }
+            }
+        }
+    }
+
+    /// Raises software generated interrupt `sgi` on `target`.
+    pub fn send_sgi(&self, sgi: u32, target: &Cpu) {
+        // Make our memory writes visible to the target before it takes the interrupt.
+        unsafe { core::arch::asm!("dsb ishst", options(nostack)) };
+        match self {
+            Gic::V2(gic) => {
+                let mask = gic.cpu_masks[target.id()].load(Ordering::Relaxed) as u32;
+                gic.gicd.write(GICD_SGIR, mask << 16 | sgi);
+            }
+            Gic::V3(_) => {
+                // Affinity levels 3 to 1 select a cluster, the range selector (RS) a group of 16
+                // cores in it and the target list one of those.
+                let mpidr = target.hw_id();
+                let aff0 = mpidr & 0xff;
+                let aff1 = mpidr >> 8 & 0xff;
+                let aff2 = mpidr >> 16 & 0xff;
+                let aff3 = mpidr >> 32 & 0xff;
+                let rs = aff0 >> 4;
+                icc::sgi1r_el1::write(
+                    aff3 << 48
+                        | rs << 44
+                        | aff2 << 32
+                        | (sgi as u64) << 24
+                        | aff1 << 16
+                        | 1 << (aff0 & 0xf),
+                );
+                barrier::isb(barrier::SY);
+            }
+        }
+    }
+
+    pub fn disable(&self, irq: u32) {
+        match self {
+            Gic::V3(gic) if irq < 32 => {
+                let sgi = gic.redistributor().offset(GICR_SGI_OFFSET);
+                sgi.set_irq_bit(GICD_ICENABLER, irq);
+            }
+            _ => self.gicd().set_irq_bit(GICD_ICENABLER, irq),
+        }
+        self.wait_for_rwp();
+    }
+
+    /// Acknowledges the highest priority pending interrupt.
+    ///
+    /// Returns the raw acknowledge value, which has to be passed back to `end_of_interrupt`. Its
+    /// low 10 bits are the interrupt ID.
+    pub fn acknowledge(&self) -> u32 {
+        match self {
+            Gic::V2(gic) => gic.gicc.read(GICC_IAR),
+            Gic::V3(_) => icc::iar1_el1::read() as u32,
+        }
+    }
+
+    pub fn end_of_interrupt(&self, iar: u32) {
+        match self {
+            Gic::V2(gic) => gic.gicc.write(GICC_EOIR, iar),
+            Gic::V3(_) => icc::eoir1_el1::write(iar as u64),
+        }
+    }
+
+    fn wait_for_rwp(&self) {
+        if let Gic::V3(gic) = self {
+            while gic.gicd.read(GICD_CTLR) & GICD_CTLR_RWP != 0 {}
+        }
+    }
+}
+
+impl GicV3 {
+    /// Finds the redistributor frame of the executing core.
+    fn redistributor(&self) -> Mmio {
+        let affinity = mpidr_affinity();
+        let mut frame = self.gicr;
+        loop {
+            let typer = frame.read64(GICR_TYPER);
+            if typer >> 32 == affinity {
+                return frame;
+            }
+            assert!(
+                typer & GICR_TYPER_LAST == 0,
+                "no GICv3 redistributor for affinity {:#x}",
+                affinity
+            );
+            frame = frame.offset(GICR_FRAME_STRIDE);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Interrupt dispatch.
+//!
+//! Drivers register a handler for an interrupt ID with [`register_handler`]. The IRQ vector calls
+//! [`handle_irq`], which acknowledges pending interrupts at the GIC and runs their handlers with
+//! interrupts masked.
+
+use core::{arch::asm, sync::atomic::Ordering};
+
+use super::{
+    fdt::FdtNode,
+    gic::{Gic, MAX_IRQS, SPURIOUS_IRQ},
+};
+use crate::{
+    cpu::{self, Cpu},
+    println,
+    singleton::Singleton,
+    sync::IrqSpinLock,
+};
+
+pub type IrqHandler = fn(irq: u32);
+
+static GIC: Singleton<Gic> = Singleton::new();
+
+static HANDLERS: IrqSpinLock<[Option<IrqHandler>; MAX_IRQS]> = IrqSpinLock::new([None; MAX_IRQS]);
+
+/// Probes the GIC from the FDT and initializes it for the boot core.
+///
+/// Interrupts stay masked at the core until [`local_irq_enable`] is called.
+pub fn irq_init() {
+    let gic = Gic::probe().expect("no usable interrupt controller in the FDT");
+    gic.init_distributor();
+    gic.init_cpu();
+    println!("Initialized GICv{}.", gic.version());
+    unsafe {
+        GIC.init(gic);
+    }
+}
+
+/// Initializes the GIC's interface to the executing secondary core.
+pub fn irq_init_cpu() {
+    GIC.get().init_cpu();
+}
+
+/// Installs `handler` for `irq` and enables the interrupt at the GIC.
+pub fn register_handler(irq: u32, handler: IrqHandler) {
+    assert!((irq as usize) < MAX_IRQS, "invalid interrupt ID {}", irq);
+    {
+        let mut handlers = HANDLERS.lock();
+        assert!(
+            handlers[irq as usize].is_none(),
+            "interrupt {} already has a handler",
+            irq
+        );
+        handlers[irq as usize] = Some(handler);
+    }
+    GIC.get().enable(irq);
+}
+
+/// Enables `irq`, which already has a handler. Used to enable per-core interrupts on secondary
+/// cores.
+pub fn enable_irq(irq: u32) {
+    GIC.get().enable(irq);
+}
+
+/// Raises software generated interrupt `sgi` (0 to 15) on `target`.
+pub fn send_sgi(sgi: u32, target: &Cpu) {
+    GIC.get().send_sgi(sgi, target);
+}
+
+/// Handles all pending interrupts. Called from the IRQ exception vector.
+pub fn handle_irq() {
+    let gic = GIC.get();
+    let in_interrupt = &cpu::current().in_interrupt;
+    in_interrupt.store(true, Ordering::Relaxed);
+    loop {
+        let iar = gic.acknowledge();
+        let irq = iar & 0x3ff;
+        if irq >= SPURIOUS_IRQ - 3 {
+            break;
+        }
+
+        let handler = HANDLERS.lock()[irq as usize];
+        match handler {
+            Some(handler) => handler(irq),
+            None => println!("Unhandled interrupt {}", irq),
+        }
+        gic.end_of_interrupt(iar);
+    }
+    in_interrupt.store(false, Ordering::Relaxed);
+}
+
+/// Returns `true` if called from an interrupt handler.
+pub fn in_interrupt() -> bool {
+    cpu::current().in_interrupt.load(Ordering::Relaxed)
+}
+
+/// Decodes the `n`-th entry of a node's `interrupts` property into an interrupt ID.
+///
+/// Entries use the GIC binding: interrupt type (0 = SPI, 1 = PPI), number and flags.
+pub fn fdt_interrupt(node: &FdtNode, n: usize) -> Option<u32> {
+    let kind = node.property_cell("interrupts", n * 3)?;
+    let number = node.property_cell("interrupts", n * 3 + 1)?;
+    match kind {
+        0 => Some(number + 32),
+        1 => Some(number + 16),
+        _ => None,
+    }
+}
+
+/// Unmasks IRQs on the executing core.
+pub fn local_irq_enable() {
+    unsafe { asm!("msr daifclr, #2", options(nostack)) };
+}
+
+/// Masks IRQs and FIQs on the executing core.
+pub fn local_irq_disable() {
+    unsafe { asm!("msr daifset, #3", options(nostack)) };
+}
+
+/// Masks IRQs and FIQs and returns the previous DAIF value for [`local_irq_restore`].
+pub fn local_irq_save() -> u64 {
+    let daif: u64;
+    unsafe {
+        asm!("mrs {}, daif", out(reg) daif, options(nostack));
+    }
+    local_irq_disable();
+    daif
+}
+
+/// Restores the interrupt mask saved by [`local_irq_save`].
+pub fn local_irq_restore(daif: u64) {
+    unsafe { asm!("msr daif, {}", in(reg) daif, options(nostack)) };
+}
+
+/// Runs `f` with IRQs masked on the executing core.
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let daif = local_irq_save();
+    let ret = f();
+    local_irq_restore(daif);
+    ret
+}
@@ -0,0 +1,383 @@
+//! Kernel translation tables.
+//!
+//! The kernel uses a 4 KiB granule with 48-bit virtual addresses on both halves. It is linked at
+//! [`KERNEL_VIRT_OFFSET`] + its physical load address and runs from TTBR1_EL1, which holds a
+//! linear map of the low [`MAPPED_GIB`] GiB of the physical address space. RAM is mapped with
+//! 2 MiB blocks, except around the kernel image, which is mapped with 4 KiB pages so that text,
+//! rodata and data can get separate permissions. The lower half (TTBR0_EL1) holds the
+//! [`AddressSpace`](super::address_space::AddressSpace) of the running user task, if any.
+//!
+//! The MMU itself is turned on by the boot trampoline in boot.s with a coarse set of early tables.
+
+use core::ptr;
+
+use cortex_a::{asm::barrier, registers::*};
+
+use crate::{
+    bsp, println,
+    utils::{align_down, align_up},
+};
+
+pub(super) const ENTRIES_PER_TABLE: usize = 512;
+
+pub(super) const PAGE_SHIFT: usize = 12;
+const L2_BLOCK_SHIFT: usize = 21;
+const L1_BLOCK_SHIFT: usize = 30;
+
+pub const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
+const L2_BLOCK_SIZE: usize = 1 << L2_BLOCK_SHIFT;
+const L1_BLOCK_SIZE: usize = 1 << L1_BLOCK_SHIFT;
+
+/// Amount of physical address space (in GiB, starting from 0) covered by the kernel tables.
+const MAPPED_GIB: usize = 4;
+
+/// Number of level 3 tables reserved for the kernel image. Each covers 2 MiB.
+const KERNEL_L3_TABLES: usize = 8;
+
+// Descriptor bits shared by all levels.
+pub(super) const DESC_VALID: u64 = 1 << 0;
+const DESC_TABLE: u64 = 1 << 1;
+const DESC_BLOCK: u64 = 0 << 1;
+const DESC_PAGE: u64 = 1 << 1;
+
+// Lower and upper attributes of block and page descriptors.
+const ATTR_INDX_SHIFT: u64 = 2;
+const AP_RW_EL1: u64 = 0b00 << 6;
+const AP_RW_EL0: u64 = 0b01 << 6;
+const AP_RO_EL1: u64 = 0b10 << 6;
+const AP_RO_EL0: u64 = 0b11 << 6;
+const SH_INNER: u64 = 0b11 << 8;
+const AF: u64 = 1 << 10;
+/// Not global: the translation is tagged with the ASID in TTBR0_EL1.
+const NG: u64 = 1 << 11;
+const PXN: u64 = 1 << 53;
+const UXN: u64 = 1 << 54;
+
+pub(super) const OUTPUT_ADDR_MASK: u64 = 0x0000_ffff_ffff_f000;
+
+/// MAIR_EL1 attribute indices, as programmed by boot.s.
+const MAIR_IDX_NORMAL: u64 = 0;
+const MAIR_IDX_DEVICE: u64 = 1;
+
+/// TCR_EL1.EPD0: disables translation table walks through TTBR0_EL1.
+pub(super) const TCR_EPD0: u64 = 1 << 7;
+
+/// Physical address of the kernel's level 0 table, loaded into TTBR1_EL1 by secondary cores in
+/// boot.s.
+#[no_mangle]
+static mut __EXT_KERNEL_TTBR1: u64 = 0;
+
+/// Base of the linear map of physical memory. Must match `KERNEL_VIRT_OFFSET` in aarch64.ld.
+pub const KERNEL_VIRT_OFFSET: usize = 0xffff_0000_0000_0000;
+
+/// Returns the linear-map virtual address of the physical address `pa`.
+pub const fn phys_to_virt(pa: usize) -> usize {
+    pa + KERNEL_VIRT_OFFSET
+}
+
+/// Returns the physical address of the linear-map virtual address `va`.
+pub fn virt_to_phys(va: usize) -> usize {
+    assert!(va >= KERNEL_VIRT_OFFSET, "{:#x} is not a kernel address", va);
+    va - KERNEL_VIRT_OFFSET
+}
+
+/// How a region of memory is mapped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryKind {
+    /// Kernel code: read-only and executable.
+    KernelText,
+    /// Kernel constants: read-only and execute-never.
+    KernelRodata,
+    /// Kernel data, stacks and free RAM: read-write and execute-never.
+    KernelData,
+    /// MMIO: Device-nGnRE, read-write and execute-never.
+    Device,
+    /// User code: read-only and executable at EL0.
+    UserCode,
+    /// User constants: read-only and execute-never.
+    UserRodata,
+    /// User data and stacks: read-write at EL0 and execute-never.
+    UserData,
+}
+
+impl MemoryKind {
+    fn attributes(self) -> u64 {
+        let normal = (MAIR_IDX_NORMAL << ATTR_INDX_SHIFT) | SH_INNER | AF;
+        match self {
+            MemoryKind::KernelText => normal | AP_RO_EL1 | UXN,
+            MemoryKind::KernelRodata => normal | AP_RO_EL1 | PXN | UXN,
+            MemoryKind::KernelData => normal | AP_RW_EL1 | PXN | UXN,
+            MemoryKind::Device => (MAIR_IDX_DEVICE << ATTR_INDX_SHIFT) | AP_RW_EL1 | AF | PXN | UXN,
+            MemoryKind::UserCode => normal | AP_RO_EL0 | NG | PXN,
+            MemoryKind::UserRodata => normal | AP_RO_EL0 | NG | PXN | UXN,
+            MemoryKind::UserData => normal | AP_RW_EL0 | NG | PXN | UXN,
+        }
+    }
+}
+
+#[repr(C, align(4096))]
+pub(super) struct PageTable {
+    pub(super) entries: [u64; ENTRIES_PER_TABLE],
+}
+
+impl PageTable {
+    pub(super) const fn empty() -> Self {
+        Self {
+            entries: [0; ENTRIES_PER_TABLE],
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.fill(0);
+    }
+
+    pub(super) fn phys_addr(&self) -> u64 {
+        virt_to_phys(self as *const Self as usize) as u64
+    }
+}
+
+struct KernelTables {
+    l0: PageTable,
+    l1: PageTable,
+    l2: [PageTable; MAPPED_GIB],
+    l3: [PageTable; KERNEL_L3_TABLES],
+}
+
+const EMPTY_TABLE: PageTable = PageTable::empty();
+
+static mut KERNEL_TABLES: KernelTables = KernelTables {
+    l0: EMPTY_TABLE,
+    l1: EMPTY_TABLE,
+    l2: [EMPTY_TABLE; MAPPED_GIB],
+    l3: [EMPTY_TABLE; KERNEL_L3_TABLES],
+};
+
+/// Physical address ranges of the kernel image, as exported by the linker script.
+struct KernelLayout {
+    text: (usize, usize),
+    rodata: (usize, usize),
+    data: (usize, usize),
+}
+
+impl KernelLayout {
+    fn get() -> Self {
+        extern "Rust" {
+            static __EXT_TEXT_START: ();
+            static __EXT_TEXT_END: ();
+            static __EXT_RODATA_START: ();
+            static __EXT_RODATA_END: ();
+            static __EXT_DATA_START: ();
+            static __EXT_DATA_END: ();
+        }
+
+        let addr = |sym: &()| virt_to_phys(sym as *const () as usize);
+        unsafe {
+            Self {
+                text: (addr(&__EXT_TEXT_START), addr(&__EXT_TEXT_END)),
+                rodata: (addr(&__EXT_RODATA_START), addr(&__EXT_RODATA_END)),
+                data: (addr(&__EXT_DATA_START), addr(&__EXT_DATA_END)),
+            }
+        }
+    }
+
+    fn start(&self) -> usize {
+        self.text.0
+    }
+
+    fn end(&self) -> usize {
+        self.data.1
+    }
+
+    fn kind_of(&self, addr: usize) -> Option<MemoryKind> {
+        let contains = |(start, end): (usize, usize)| start <= addr && addr < end;
+        if contains(self.text) {
+            Some(MemoryKind::KernelText)
+        } else if contains(self.rodata) {
+            Some(MemoryKind::KernelRodata)
+        } else if contains(self.data) {
+            Some(MemoryKind::KernelData)
+        } else {
+            None
+        }
+    }
+}
+
+pub(super) fn table_desc(table: &PageTable) -> u64 {
+    table.phys_addr() | DESC_TABLE | DESC_VALID
+}
+
+fn block_desc(addr: usize, kind: MemoryKind) -> u64 {
+    (addr as u64 & OUTPUT_ADDR_MASK) | kind.attributes() | DESC_BLOCK | DESC_VALID
+}
+
+pub(super) fn page_desc(addr: usize, kind: MemoryKind) -> u64 {
+    (addr as u64 & OUTPUT_ADDR_MASK) | kind.attributes() | DESC_PAGE | DESC_VALID
+}
+
+/// The physical `[start, end)` ranges mapped as device memory: `bsp::DEVICE_MEMORY`, rounded out
+/// to whole L2 blocks.
+fn device_windows() -> impl Iterator<Item = (usize, usize)> {
+    bsp::DEVICE_MEMORY.iter().map(|&(start, size)| {
+        (
+            align_down(start, L2_BLOCK_SIZE),
+            align_up(start + size, L2_BLOCK_SIZE),
+        )
+    })
+}
+
+/// Returns whether the kernel tables map `[pa, pa + size)` as device memory.
+pub fn is_device_mapped(pa: usize, size: usize) -> bool {
+    device_windows().any(|(start, end)| start <= pa && pa + size <= end)
+}
+
+/// Fills the static kernel tables.
+///
+/// `ram` is the `(start, size)` of the RAM reported by the FDT. Anything outside of the RAM, the
+/// kernel image and `bsp::DEVICE_MEMORY` is left unmapped.
+fn build_tables(tables: &mut KernelTables, ram: (usize, usize)) {
+    let layout = KernelLayout::get();
+    let mapped_end = MAPPED_GIB * L1_BLOCK_SIZE;
+    let (ram_start, ram_end) = (ram.0, ram.0 + ram.1);
+    if ram_end > mapped_end {
+        println!(
+            "MMU: RAM beyond {:#x} is not mapped (RAM ends at {:#x})",
+            mapped_end, ram_end
+        );
+    }
+
+    let is_ram = |addr: usize| ram_start <= addr && addr < ram_end;
+    let is_device = |addr: usize| device_windows().any(|(start, end)| start <= addr && addr < end);
+
+    let kernel_first_block = align_down(layout.start(), L2_BLOCK_SIZE);
+    let kernel_last_block = align_up(layout.end(), L2_BLOCK_SIZE);
+    assert!(
+        (kernel_last_block - kernel_first_block) / L2_BLOCK_SIZE <= KERNEL_L3_TABLES,
+        "kernel image is too large for the early page tables"
+    );
+
+    tables.l0.clear();
+    tables.l1.clear();
+    tables.l0.entries[0] = table_desc(&tables.l1);
+
+    let mut next_l3 = 0;
+    for (gib, l2) in tables.l2.iter_mut().enumerate() {
+        tables.l1.entries[gib] = table_desc(l2);
+
+        for (i, entry) in l2.entries.iter_mut().enumerate() {
+            let block = gib * L1_BLOCK_SIZE + i * L2_BLOCK_SIZE;
+
+            *entry = if kernel_first_block <= block && block < kernel_last_block {
+                let l3 = &mut tables.l3[next_l3];
+                next_l3 += 1;
+                for (j, page) in l3.entries.iter_mut().enumerate() {
+                    let addr = block + j * PAGE_SIZE;
+                    *page = match layout.kind_of(addr) {
+                        Some(kind) => page_desc(addr, kind),
+                        None if is_ram(addr) => page_desc(addr, MemoryKind::KernelData),
+                        None => 0,
+                    };
+                }
+                table_desc(l3)
+            } else if is_device(block) {
+                block_desc(block, MemoryKind::Device)
+            } else if is_ram(block) && is_ram(block + L2_BLOCK_SIZE - 1) {
+                block_desc(block, MemoryKind::KernelData)
+            } else {
+                0
+            };
+        }
+    }
+}
+
+/// Builds the kernel translation tables and switches TTBR1_EL1 over to them.
+///
+/// Walks through TTBR0_EL1 are disabled afterwards, which drops the identity map used by the boot
+/// trampoline and leaves the lower half of the address space unmapped.
+///
+/// # Safety
+///
+/// - Must be called once, while running on the early tables installed by boot.s.
+/// - `ram` must be the physical `(start, size)` of the RAM the kernel is running from.
+pub unsafe fn mmu_init(ram: (usize, usize)) {
+    let tables = unsafe { &mut *ptr::addr_of_mut!(KERNEL_TABLES) };
+    build_tables(tables, ram);
+
+    unsafe {
+        // Secondary cores read this with their MMU off, so it has to reach memory.
+        __EXT_KERNEL_TTBR1 = tables.l0.phys_addr();
+        core::arch::asm!(
+            "dc cvac, {}",
+            "dsb ishst",
+            in(reg) ptr::addr_of!(__EXT_KERNEL_TTBR1),
+            options(nostack)
+        );
+        TTBR1_EL1.set(tables.l0.phys_addr());
+        TCR_EL1.set(TCR_EL1.get() | TCR_EPD0);
+        barrier::isb(barrier::SY);
+        core::arch::asm!("tlbi vmalle1", "dsb ish", options(nostack));
+        barrier::isb(barrier::SY);
+    }
+
+    println!("Switched to the kernel page tables.");
+}
+
+/// Above this many pages, [`flush_tlb_asid_range`] invalidates the whole ASID instead.
+const TLB_FLUSH_MAX_PAGES: usize = 64;
+
+/// VA[55:12] in a TLBI operand. The bits above hold the TTL hint and the ASID.
+const TLBI_VA_MASK: usize = (1 << 44) - 1;
+
+/// Invalidates the cached translations of `[va, va + len)` tagged with `asid` on every core.
+///
+/// Must be called after a mapping has been changed or removed, before the memory is reused. The
+/// inner shareable TLBI instructions are broadcast by the hardware, so no IPI is needed.
+pub fn flush_tlb_asid_range(asid: u16, va: usize, len: usize) {
+    let start = align_down(va, PAGE_SIZE);
+    let end = align_up(va + len, PAGE_SIZE);
+    if (end - start) / PAGE_SIZE > TLB_FLUSH_MAX_PAGES {
+        flush_tlb_asid(asid);
+        return;
+    }
+
+    let asid = (asid as usize) << 48;
+    unsafe {
+        // Make the page table update visible to the table walkers first.
+        core::arch::asm!("dsb ishst", options(nostack));
+        for page in (start..end).step_by(PAGE_SIZE) {
+            let operand = asid | (page >> PAGE_SHIFT) & TLBI_VA_MASK;
+            core::arch::asm!("tlbi vae1is, {}", in(reg) operand, options(nostack));
+        }
+        core::arch::asm!("dsb ish", "isb", options(nostack));
+    }
+}
+
+/// Invalidates all cached translations tagged with `asid` on every core.
+pub fn flush_tlb_asid(asid: u16) {
+    unsafe {
+        core::arch::asm!(
+            "dsb ishst",
+            "tlbi aside1is, {}",
+            "dsb ish",
+            "isb",
+            in(reg) (asid as u64) << 48,
+            options(nostack)
+        );
+    }
+}
+
+/// Finishes the switch to the kernel tables on a secondary core.
+///
+/// boot.s has already loaded TTBR1_EL1 from `__EXT_KERNEL_TTBR1`; this turns off the identity map
+/// in TTBR0_EL1 that was needed to get there.
+///
+/// # Safety
+///
+/// Must be called on a secondary core, once it runs from the higher half.
+pub unsafe fn mmu_init_secondary() {
+    unsafe {
+        TCR_EL1.set(TCR_EL1.get() | TCR_EPD0);
+        barrier::isb(barrier::SY);
+        core::arch::asm!("tlbi vmalle1", "dsb nsh", options(nostack));
+        barrier::isb(barrier::SY);
+    }
+}
@@ -1,12 +1,26 @@
+pub mod address_space;
+#[cfg(feature = "alloc_tracking")]
+pub mod backtrace;
 pub mod boot;
+pub mod esr;
 pub mod exception;
 pub mod fdt;
+pub mod gic;
+pub mod irq;
+pub mod mmu;
+#[cfg(feature = "debug_shell")]
+pub mod probe;
+pub mod psci;
+pub mod smp;
 pub mod thread;
+pub mod timer;
+pub mod user;
 
 pub fn system_off() -> ! {
-    extern "Rust" {
-        fn __system_off() -> !;
-    }
+    psci::system_off()
+}
 
-    unsafe { __system_off() }
+/// Reboots. Only returns if that failed.
+pub fn system_reset() -> psci::PsciError {
+    psci::system_reset()
 }
@@ -0,0 +1,49 @@
+//! Accessing kernel addresses that may not be mapped, for the debug shell.
+//!
+//! A data abort on the access itself is recovered from through a fault fixup and reported as an
+//! error. Asynchronous aborts, e.g. from a device that does not answer, are not.
+
+use super::exception;
+
+core::arch::global_asm!(include_str!("probe.s"));
+
+extern "C" {
+    fn __probe_read_u32(addr: *const u32, value: *mut u32) -> usize;
+    fn __probe_write_u32(addr: *mut u32, value: u32) -> usize;
+    fn __probe_end();
+    fn __probe_fixup();
+}
+
+/// The address is not mapped, or not with the needed permissions.
+#[derive(Clone, Copy, Debug)]
+pub struct ProbeFault;
+
+/// Makes faults in [`read_u32`] and [`write_u32`] recoverable.
+pub fn probe_init() {
+    let start = __probe_read_u32 as unsafe extern "C" fn(*const u32, *mut u32) -> usize as usize;
+    exception::register_fault_fixup(
+        start..__probe_end as unsafe extern "C" fn() as usize,
+        __probe_fixup as unsafe extern "C" fn() as usize,
+    );
+}
+
+/// Reads the 32-bit word at the kernel address `addr`.
+pub fn read_u32(addr: usize) -> Result<u32, ProbeFault> {
+    let mut value = 0;
+    match unsafe { __probe_read_u32(addr as *const u32, &mut value) } {
+        0 => Ok(value),
+        _ => Err(ProbeFault),
+    }
+}
+
+/// Writes the 32-bit word at the kernel address `addr`.
+///
+/// # Safety
+///
+/// Anything mapped at `addr` may be overwritten, including the kernel's own data.
+pub unsafe fn write_u32(addr: usize, value: u32) -> Result<(), ProbeFault> {
+    match unsafe { __probe_write_u32(addr as *mut u32, value) } {
+        0 => Ok(()),
+        _ => Err(ProbeFault),
+    }
+}
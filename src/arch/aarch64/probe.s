@@ -0,0 +1,26 @@
+.globl __probe_read_u32
+.globl __probe_write_u32
+.globl __probe_end
+.globl __probe_fixup
+
+.section .text
+
+// Loads the 32-bit word at x0 into [x1] and returns 0. A fault resumes at `__probe_fixup`.
+__probe_read_u32:
+    ldr w2, [x0]
+    str w2, [x1]
+    mov x0, #0
+    ret
+
+// Stores w1 to the 32-bit word at x0 and returns 0. A fault resumes at `__probe_fixup`.
+__probe_write_u32:
+    str w1, [x0]
+    mov x0, #0
+    ret
+__probe_end:
+
+__probe_fixup:
+    mov x0, #1
+    ret
+
+// vim: filetype=arm
@@ -0,0 +1,157 @@
+//! Power State Coordination Interface.
+//!
+//! PSCI is implemented by the firmware (or hypervisor) and reached with `hvc` or `smc`, as given
+//! by the `method` property of the FDT `psci` node. Until [`psci_init`] has run, calls go over
+//! `hvc` with the standard function IDs, which is what QEMU's `virt` machine expects.
+
+use core::arch::asm;
+
+use super::fdt;
+use crate::{println, singleton::Singleton};
+
+const PSCI_COMPATIBLE_V0_2: &[&str] = &["arm,psci-1.0", "arm,psci-0.2"];
+const PSCI_COMPATIBLE_V0_1: &str = "arm,psci";
+
+// Function IDs defined by PSCI 0.2 and later.
+const PSCI_CPU_ON_64: u32 = 0xc400_0003;
+const PSCI_SYSTEM_OFF: u32 = 0x8400_0008;
+const PSCI_SYSTEM_RESET: u32 = 0x8400_0009;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Conduit {
+    Hvc,
+    Smc,
+}
+
+struct Psci {
+    conduit: Conduit,
+    cpu_on: u32,
+    system_off: Option<u32>,
+    system_reset: Option<u32>,
+}
+
+static PSCI: Singleton<Psci> = Singleton::new_with(Psci {
+    conduit: Conduit::Hvc,
+    cpu_on: PSCI_CPU_ON_64,
+    system_off: Some(PSCI_SYSTEM_OFF),
+    system_reset: Some(PSCI_SYSTEM_RESET),
+});
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PsciError {
+    NotSupported,
+    InvalidParameters,
+    Denied,
+    AlreadyOn,
+    OnPending,
+    InternalFailure,
+    NotPresent,
+    Disabled,
+    InvalidAddress,
+    Unknown(i64),
+}
+
+impl PsciError {
+    fn from_return(ret: i64) -> Result<(), PsciError> {
+        match ret {
+            0 => Ok(()),
+            -1 => Err(PsciError::NotSupported),
+            -2 => Err(PsciError::InvalidParameters),
+            -3 => Err(PsciError::Denied),
+            -4 => Err(PsciError::AlreadyOn),
+            -5 => Err(PsciError::OnPending),
+            -6 => Err(PsciError::InternalFailure),
+            -7 => Err(PsciError::NotPresent),
+            -8 => Err(PsciError::Disabled),
+            -9 => Err(PsciError::InvalidAddress),
+            _ => Err(PsciError::Unknown(ret)),
+        }
+    }
+}
+
+/// Reads the conduit and function IDs from the FDT.
+///
+/// # Safety
+///
+/// Must be called before other cores are started.
+pub unsafe fn psci_init() {
+    let node = match fdt::fdt_nodes().find(|node| node.is_compatible(PSCI_COMPATIBLE_V0_1)) {
+        Some(node) => node,
+        None => {
+            println!("No PSCI node in the FDT, assuming hvc.");
+            return;
+        }
+    };
+
+    let psci = unsafe { PSCI.get_mut() };
+    psci.conduit = match node.property("method") {
+        Some(b"smc\0") => Conduit::Smc,
+        _ => Conduit::Hvc,
+    };
+    // PSCI 0.1 has no standard function IDs; they are listed in the node instead.
+    if !PSCI_COMPATIBLE_V0_2.iter().any(|c| node.is_compatible(c)) {
+        if let Some(cpu_on) = node.property_cell("cpu_on", 0) {
+            psci.cpu_on = cpu_on;
+        }
+        psci.system_off = None;
+        psci.system_reset = None;
+    }
+    println!("PSCI: calls over {:?}", psci.conduit);
+}
+
+fn call(function: u32, arg0: u64, arg1: u64, arg2: u64) -> i64 {
+    let mut ret = function as u64;
+    unsafe {
+        match PSCI.get().conduit {
+            Conduit::Hvc => asm!(
+                "hvc #0",
+                inout("x0") ret,
+                inout("x1") arg0 => _,
+                inout("x2") arg1 => _,
+                inout("x3") arg2 => _,
+                options(nostack)
+            ),
+            Conduit::Smc => asm!(
+                "smc #0",
+                inout("x0") ret,
+                inout("x1") arg0 => _,
+                inout("x2") arg1 => _,
+                inout("x3") arg2 => _,
+                options(nostack)
+            ),
+        }
+    }
+    ret as i64
+}
+
+/// Starts the core with MPIDR affinity `target` at physical address `entry`, with MMU and caches
+/// off and `context` in x0.
+pub fn cpu_on(target: u64, entry: usize, context: usize) -> Result<(), PsciError> {
+    PsciError::from_return(call(
+        PSCI.get().cpu_on,
+        target,
+        entry as u64,
+        context as u64,
+    ))
+}
+
+/// Powers the system off.
+pub fn system_off() -> ! {
+    if let Some(system_off) = PSCI.get().system_off {
+        call(system_off, 0, 0, 0);
+    }
+    loop {
+        cortex_a::asm::wfe();
+    }
+}
+
+/// Resets the system. Only returns if that failed.
+pub fn system_reset() -> PsciError {
+    match PSCI.get().system_reset {
+        Some(system_reset) => match PsciError::from_return(call(system_reset, 0, 0, 0)) {
+            Err(error) => error,
+            Ok(()) => PsciError::Unknown(0),
+        },
+        None => PsciError::NotSupported,
+    }
+}
@@ -1,4 +1,5 @@
 .globl __context_switch
+.globl __thread_trampoline
 
 .section .text
 
@@ -22,3 +23,9 @@ __context_switch:
     mov sp, x9
 
     ret
+
+// First code run by a new thread: calls `entry(arg)`, which are placed in x20 and x19 by
+// `ThreadContext::new`.
+__thread_trampoline:
+    mov x0, x19
+    br x20
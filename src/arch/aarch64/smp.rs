@@ -0,0 +1,95 @@
+//! Secondary core bring-up.
+//!
+//! Secondary cores are listed as `cpu` nodes in the FDT and started with PSCI `CPU_ON`. They enter
+//! at `__secondary_start` in boot.s, which drops to EL1, turns on the MMU with the kernel tables
+//! and calls [`_secondary_main`] on the boot stack prepared by [`smp_init`].
+
+use alloc::vec;
+use core::time::Duration;
+
+use cortex_a::registers::*;
+
+use super::{exception, fdt, irq, mmu, psci, timer};
+use crate::{
+    cpu::{self, Cpu},
+    println, time,
+};
+
+const BOOT_STACK_SIZE: usize = 64 * 1024;
+
+/// How long to wait for a started core to come online.
+const CPU_ON_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Bits of MPIDR_EL1 that identify a core.
+const MPIDR_AFFINITY_MASK: u64 = 0xff_00ff_ffff;
+
+/// Returns the executing core's hardware ID, in the format of the `reg` property of FDT `cpu`
+/// nodes.
+pub fn hw_id() -> u64 {
+    MPIDR_EL1.get() & MPIDR_AFFINITY_MASK
+}
+
+pub fn set_current_cpu(cpu: &'static Cpu) {
+    TPIDR_EL1.set(cpu as *const Cpu as u64);
+}
+
+pub fn current_cpu() -> &'static Cpu {
+    unsafe { &*(TPIDR_EL1.get() as *const Cpu) }
+}
+
+/// Starts all secondary cores listed in the FDT and waits for them to come online.
+pub fn smp_init() {
+    extern "C" {
+        fn __secondary_start();
+    }
+
+    let entry = mmu::virt_to_phys(__secondary_start as unsafe extern "C" fn() as usize);
+    let boot_hw_id = hw_id();
+    let cpu_nodes = fdt::fdt_nodes().filter(|node| node.property("device_type") == Some(b"cpu\0"));
+    for node in cpu_nodes {
+        let target = match node.reg(0) {
+            Some((target, _)) if target != boot_hw_id => target,
+            _ => continue,
+        };
+        let cpu = match cpu::add_cpu(target) {
+            Some(cpu) => cpu,
+            None => {
+                println!(
+                    "Ignoring CPU {:#x}: more than {} CPUs",
+                    target,
+                    cpu::MAX_CPUS
+                );
+                continue;
+            }
+        };
+
+        let stack = vec![0u8; BOOT_STACK_SIZE].leak();
+        cpu.set_boot_stack_top(stack.as_ptr_range().end as usize);
+
+        if let Err(err) = psci::cpu_on(target, entry, cpu as *const Cpu as usize) {
+            println!("Failed to start CPU {:#x}: {:?}", target, err);
+            continue;
+        }
+        let deadline = time::now() + CPU_ON_TIMEOUT;
+        while !cpu.is_online() && time::now() < deadline {
+            core::hint::spin_loop();
+        }
+        if !cpu.is_online() {
+            println!("CPU {:#x} did not come online", target);
+        }
+    }
+}
+
+/// Rust entry point of secondary cores, called by boot.s with the MMU on.
+#[no_mangle]
+extern "C" fn _secondary_main(cpu: &'static Cpu) -> ! {
+    set_current_cpu(cpu);
+    unsafe {
+        mmu::mmu_init_secondary();
+        exception::handling_init();
+    }
+    irq::irq_init_cpu();
+    timer::timer_init_cpu();
+    crate::ipi::ipi_init_cpu();
+    crate::secondary_main()
+}
@@ -1,7 +1,7 @@
-use crate::thread::Thread;
+use alloc::sync::Arc;
 
-use cortex_a::registers::TPIDR_EL1;
-use tock_registers::interfaces::*;
+use super::address_space;
+use crate::thread::Thread;
 
 core::arch::global_asm!(include_str!("routine.s"));
 
@@ -24,26 +24,34 @@ pub struct ThreadContext {
 }
 
 impl ThreadContext {
-    pub fn new(func: fn(), stack: *const u8) -> Self {
+    /// Creates a context that calls `entry(arg)` on `stack` when first switched to.
+    pub fn new(entry: extern "C" fn(usize) -> !, arg: usize, stack: *const u8) -> Self {
+        extern "C" {
+            fn __thread_trampoline();
+        }
+
         Self {
-            lr: func as u64,
+            x19: arg as u64,
+            x20: entry as u64,
+            lr: __thread_trampoline as unsafe extern "C" fn() as u64,
             sp: stack as u64,
             ..Self::default()
         }
     }
 }
 
-pub fn current_thread() -> *mut () {
-    TPIDR_EL1.get() as *mut _
-}
-
 pub fn thread_switch(from: *mut Thread, to: *mut Thread) {
     extern "C" {
         fn __context_switch(from: *mut ThreadContext, to: *mut ThreadContext);
     }
 
     unsafe {
-        TPIDR_EL1.set(to as u64);
+        match ((*from).address_space(), (*to).address_space()) {
+            (Some(from_space), Some(to_space)) if Arc::ptr_eq(from_space, to_space) => {}
+            (_, Some(to_space)) => to_space.activate(),
+            (Some(_), None) => address_space::deactivate(),
+            (None, None) => {}
+        }
         __context_switch(&mut (*from).context as *mut _, &mut (*to).context as *mut _);
     }
 }
@@ -0,0 +1,79 @@
+//! ARM generic timer.
+//!
+//! The EL1 physical timer (CNTP) raises a periodic tick at [`TICK_HZ`], which drives preemption in
+//! `thread`.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use cortex_a::{asm::barrier, registers::*};
+
+use super::{fdt, irq};
+use crate::{println, thread, time};
+
+/// Frequency of the scheduler tick.
+pub const TICK_HZ: u64 = 100;
+
+const TIMER_COMPATIBLE: &[&str] = &["arm,armv8-timer", "arm,armv7-timer"];
+
+/// Index of the EL1 physical timer in the timer node's `interrupts` property.
+const FDT_NON_SECURE_PHYS_TIMER: usize = 1;
+
+/// Architectural PPI of the EL1 physical timer, used when the FDT has no timer node.
+const DEFAULT_PHYS_TIMER_IRQ: u32 = 30;
+
+static TIMER_IRQ: AtomicU32 = AtomicU32::new(DEFAULT_PHYS_TIMER_IRQ);
+
+/// Frequency of the system counter in Hz.
+pub fn frequency() -> u64 {
+    CNTFRQ_EL0.get()
+}
+
+/// Current value of the system counter.
+pub fn counter() -> u64 {
+    // Keep the read from being speculated ahead of preceding instructions.
+    barrier::isb(barrier::SY);
+    CNTPCT_EL0.get()
+}
+
+fn ticks_per_interval() -> u64 {
+    frequency() / TICK_HZ
+}
+
+fn timer_irq() -> u32 {
+    fdt::fdt_nodes()
+        .find(|node| TIMER_COMPATIBLE.iter().any(|c| node.is_compatible(c)))
+        .and_then(|node| irq::fdt_interrupt(&node, FDT_NON_SECURE_PHYS_TIMER))
+        .unwrap_or(DEFAULT_PHYS_TIMER_IRQ)
+}
+
+fn handle_tick(_irq: u32) {
+    // Rearm first, which also deasserts the level-triggered interrupt.
+    CNTP_TVAL_EL0.set(ticks_per_interval());
+    time::run_expired_timers();
+    thread::tick();
+}
+
+fn start_tick() {
+    CNTP_TVAL_EL0.set(ticks_per_interval());
+    CNTP_CTL_EL0.write(CNTP_CTL_EL0::ENABLE::SET + CNTP_CTL_EL0::IMASK::CLEAR);
+}
+
+/// Starts the periodic tick on the boot core.
+pub fn timer_init() {
+    let irq = timer_irq();
+    TIMER_IRQ.store(irq, Ordering::Relaxed);
+    start_tick();
+    irq::register_handler(irq, handle_tick);
+    println!(
+        "Timer: {} Hz counter, {} Hz tick on interrupt {}",
+        frequency(),
+        TICK_HZ,
+        irq
+    );
+}
+
+/// Starts the periodic tick on a secondary core.
+pub fn timer_init_cpu() {
+    start_tick();
+    irq::enable_irq(TIMER_IRQ.load(Ordering::Relaxed));
+}
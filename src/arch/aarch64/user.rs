@@ -0,0 +1,101 @@
+//! Running threads at EL0.
+//!
+//! A user thread is a kernel thread with an [`AddressSpace`](super::address_space::AddressSpace)
+//! that calls [`enter_user`]. Every exception it takes from EL0 saves its registers as an
+//! `ExceptionContext` on its kernel stack, and returning from the exception resumes it. This trap
+//! frame is where system calls find their arguments and leave their result. [`exit_user`] makes
+//! `enter_user` return, discarding the frame.
+
+use core::slice;
+
+use super::{address_space::USER_END, exception, exception::ExceptionContext, irq};
+use crate::{arch::thread::ThreadContext, thread::Thread};
+
+core::arch::global_asm!(include_str!("user.s"));
+
+extern "C" {
+    fn __copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn __copy_user_end();
+    fn __copy_user_fixup();
+}
+
+/// A user address range that is out of bounds or not mapped with the needed permissions.
+#[derive(Clone, Copy, Debug)]
+pub struct UserFault;
+
+/// Makes faults in [`copy_from_user`] and [`copy_to_user`] recoverable.
+pub fn user_init() {
+    let start = __copy_user as unsafe extern "C" fn(*mut u8, *const u8, usize) -> usize as usize;
+    exception::register_fault_fixup(
+        start..__copy_user_end as unsafe extern "C" fn() as usize,
+        __copy_user_fixup as unsafe extern "C" fn() as usize,
+    );
+}
+
+/// Runs the current thread at EL0 from `entry`, with `sp` as its stack pointer and `arg` in x0.
+///
+/// Returns the exit code once the thread calls [`exit_user`].
+pub fn enter_user(entry: usize, sp: usize, arg: usize) -> i32 {
+    extern "C" {
+        fn __enter_user(frame: *const ExceptionContext, saved: *mut ThreadContext) -> i64;
+    }
+
+    let frame = ExceptionContext::new_user(entry, sp, arg);
+    let thread = Thread::current_ptr();
+    // ELR_EL1 and SPSR_EL1 must survive until the `eret`.
+    irq::local_irq_disable();
+    unsafe { __enter_user(&frame, &mut (*thread).user_return) as i32 }
+}
+
+/// Leaves EL0 for good: returns `code` from the current thread's [`enter_user`].
+///
+/// Must be called on behalf of a user thread, from an exception taken at EL0.
+pub fn exit_user(code: i32) -> ! {
+    extern "C" {
+        fn __exit_user(saved: *const ThreadContext, code: i64) -> !;
+    }
+
+    let thread = Thread::current_ptr();
+    irq::local_irq_enable();
+    unsafe { __exit_user(&(*thread).user_return, code as i64) }
+}
+
+/// Checks that `[addr, addr + len)` is within the user part of the address space.
+pub fn check_user_range(addr: usize, len: usize) -> Result<(), UserFault> {
+    match addr.checked_add(len) {
+        Some(end) if end <= USER_END => Ok(()),
+        _ => Err(UserFault),
+    }
+}
+
+/// Copies `dst.len()` bytes from the user address `src` in the current address space.
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<(), UserFault> {
+    check_user_range(src, dst.len())?;
+    match unsafe { __copy_user(dst.as_mut_ptr(), src as *const u8, dst.len()) } {
+        0 => Ok(()),
+        _ => Err(UserFault),
+    }
+}
+
+/// Copies `src` to the user address `dst` in the current address space.
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), UserFault> {
+    check_user_range(dst, src.len())?;
+    match unsafe { __copy_user(dst as *mut u8, src.as_ptr(), src.len()) } {
+        0 => Ok(()),
+        _ => Err(UserFault),
+    }
+}
+
+/// The ELF image of the demo program in user.s.
+pub fn demo_program() -> &'static [u8] {
+    extern "Rust" {
+        static __EXT_USER_DEMO_START: u8;
+        static __EXT_USER_DEMO_END: u8;
+    }
+
+    unsafe {
+        let start = &__EXT_USER_DEMO_START as *const u8;
+        let end = &__EXT_USER_DEMO_END as *const u8;
+        slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
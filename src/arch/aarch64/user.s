@@ -0,0 +1,151 @@
+.globl __enter_user
+.globl __exit_user
+.globl __copy_user
+.globl __copy_user_end
+.globl __copy_user_fixup
+.globl __EXT_USER_DEMO_START
+.globl __EXT_USER_DEMO_END
+
+.section .text
+
+// Saves the kernel context to x1 and drops to EL0 with the exception context at x0.
+//
+// Must be called with IRQs masked. A later `__exit_user` with the same saved context returns from
+// here.
+__enter_user:
+    mov x9, sp
+    stp x19, x20, [x1], #16
+    stp x21, x22, [x1], #16
+    stp x23, x24, [x1], #16
+    stp x25, x26, [x1], #16
+    stp x27, x28, [x1], #16
+    stp x29, lr, [x1], #16
+    str x9, [x1]
+
+    // Copy the context to where CALL_WITH_CONTEXT would have put it and return through it.
+    sub sp, sp, #16 * 18
+    mov x9, sp
+    mov x10, #18
+1:  ldp x11, x12, [x0], #16
+    stp x11, x12, [x9], #16
+    subs x10, x10, #1
+    b.ne 1b
+    b __exception_restore_context
+
+// Restores the kernel context saved at x0 by `__enter_user`, which then returns x1.
+__exit_user:
+    ldp x19, x20, [x0], #16
+    ldp x21, x22, [x0], #16
+    ldp x23, x24, [x0], #16
+    ldp x25, x26, [x0], #16
+    ldp x27, x28, [x0], #16
+    ldp x29, lr, [x0], #16
+    ldr x9, [x0]
+    mov sp, x9
+    mov x0, x1
+    ret
+
+// Copies x2 bytes from x1 to x0 and returns the number of bytes that were not copied.
+//
+// Either side may be a user address. A fault on one of them resumes at `__copy_user_fixup` with
+// x2 still counting the byte that faulted.
+__copy_user:
+    cbz x2, 2f
+1:  ldrb w3, [x1], #1
+    strb w3, [x0], #1
+    subs x2, x2, #1
+    b.ne 1b
+2:  mov x0, x2
+    ret
+__copy_user_end:
+
+__copy_user_fixup:
+    mov x0, x2
+    ret
+
+// A minimal ELF executable that exercises the system calls, run by `init`. The numbers in x8 must
+// match `SYS_*` in syscall.rs.
+.equ USER_DEMO_BASE, 0x400000
+
+.section .rodata
+.balign 8
+__EXT_USER_DEMO_START:
+    // ELF header
+    .byte 0x7f, 'E', 'L', 'F', 2, 1, 1, 0                    // ELFCLASS64, ELFDATA2LSB, EV_CURRENT
+    .quad 0
+    .short 2                                                // e_type: ET_EXEC
+    .short 183                                              // e_machine: EM_AARCH64
+    .word 1                                                 // e_version
+    .quad USER_DEMO_BASE + (.Luser_demo_entry - __EXT_USER_DEMO_START)
+    .quad .Luser_demo_phdr - __EXT_USER_DEMO_START          // e_phoff
+    .quad 0                                                 // e_shoff
+    .word 0                                                 // e_flags
+    .short 64                                               // e_ehsize
+    .short 56                                               // e_phentsize
+    .short 1                                                // e_phnum
+    .short 64, 0, 0                                         // e_shentsize, e_shnum, e_shstrndx
+
+.Luser_demo_phdr:
+    .word 1                                                 // p_type: PT_LOAD
+    .word 5                                                 // p_flags: PF_R | PF_X
+    .quad 0                                                 // p_offset
+    .quad USER_DEMO_BASE                                    // p_vaddr
+    .quad USER_DEMO_BASE                                    // p_paddr
+    .quad __EXT_USER_DEMO_END - __EXT_USER_DEMO_START       // p_filesz
+    .quad __EXT_USER_DEMO_END - __EXT_USER_DEMO_START       // p_memsz
+    .quad 0x1000                                            // p_align
+
+.Luser_demo_entry:
+    // write(1, message, length)
+    mov x0, #1
+    adr x1, 3f
+    adr x2, 4f
+    sub x2, x2, x1
+    mov x8, #0
+    svc #0
+
+    // write(1, argv[0], strlen(argv[0]))
+    ldr x1, [sp, #8]
+    mov x2, #0
+1:  ldrb w3, [x1, x2]
+    cbz w3, 2f
+    add x2, x2, #1
+    b 1b
+2:  mov x0, #1
+    mov x8, #0
+    svc #0
+
+    // write(1, "\n", 1)
+    mov x0, #1
+    adr x1, 4f
+    mov x2, #1
+    mov x8, #0
+    svc #0
+
+    // sleep(10)
+    mov x0, #10
+    mov x8, #3
+    svc #0
+
+    // mmap(0, 4096, PROT_READ | PROT_WRITE), and exit with a value stored in the new page.
+    mov x0, #0
+    mov x1, #4096
+    mov x2, #3
+    mov x8, #5
+    svc #0
+    tbnz x0, #63, 5f
+    mov x1, #42
+    str x1, [x0]
+    ldr x0, [x0]
+
+    // exit(x0)
+5:  mov x8, #2
+    svc #0
+    b 5b
+
+3:  .ascii "Hello from EL0, this is "
+4:  .ascii "\n"
+.balign 8
+__EXT_USER_DEMO_END:
+
+// vim: filetype=arm
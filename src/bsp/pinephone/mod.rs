@@ -1,32 +1,37 @@
 use core::ptr;
 use embedded_hal::serial;
 
+use crate::arch::mmu::phys_to_virt;
+use crate::time::{self, Duration};
+
 pub const BOARD_NAME: &'static str = "Pinephone";
 
+/// `(start, size)` of the MMIO windows that are mapped as device memory: CCU, UART0 and GIC.
+pub const DEVICE_MEMORY: &[(usize, usize)] = &[(0x01C0_0000, 0x0040_0000)];
+
+/// Interrupt ID of UART0 (SPI 0).
+pub const SERIAL_IRQ: u32 = 32;
+
 pub struct Serial;
 
-const CCU_BUS_CLK_GATING_REG3: *mut u32 = 0x01C2006C as *mut _;
+const CCU_BUS_CLK_GATING_REG3: *mut u32 = phys_to_virt(0x01C2006C) as *mut _;
 const BUS_CLK_GATING_REG3_UART0_GATING: u32 = 1u32 << 16;
 
-const UART0_USR: *mut u32 = 0x01C2807C as *mut _;
+const UART0_USR: *mut u32 = phys_to_virt(0x01C2807C) as *mut _;
 const UART_USR_BUSY: u32 = 1 << 0;
-const UART0_LCR: *mut u32 = 0x01C2800C as *mut _;
+const UART0_LCR: *mut u32 = phys_to_virt(0x01C2800C) as *mut _;
 const UART_LCR_DLAB: u32 = 1 << 7;
-const UART0_DLL: *mut u32 = 0x01C28000 as *mut _;
-const UART0_LSR: *mut u32 = 0x01C28014 as *mut _;
+const UART0_DLL: *mut u32 = phys_to_virt(0x01C28000) as *mut _;
+const UART0_LSR: *mut u32 = phys_to_virt(0x01C28014) as *mut _;
+const UART_LSR_DR: u32 = 1 << 0;
 const UART_LSR_THRE: u32 = 1 << 5;
-const UART0_THR: *mut u32 = 0x01C28000 as *mut _;
-const UART0_FCR: *mut u32 = 0x01C28008 as *mut _;
+const UART0_RBR: *mut u32 = phys_to_virt(0x01C28000) as *mut _;
+const UART0_IER: *mut u32 = phys_to_virt(0x01C28004) as *mut _;
+const UART_IER_ERBFI: u32 = 1 << 0;
+const UART0_THR: *mut u32 = phys_to_virt(0x01C28000) as *mut _;
+const UART0_FCR: *mut u32 = phys_to_virt(0x01C28008) as *mut _;
 const UART_FCR_FIFOE: u32 = 1 << 0;
 
-fn sleep() {
-    for _ in 0..100_000_000 {
-        unsafe {
-            core::arch::asm!("nop");
-        }
-    }
-}
-
 impl Serial {
     pub fn new() -> Self {
         let mut s = Self;
@@ -41,7 +46,7 @@ impl Serial {
     fn setup_serial(&mut self) {
         unsafe {
             ptr::write_volatile(CCU_BUS_CLK_GATING_REG3, BUS_CLK_GATING_REG3_UART0_GATING);
-            sleep();
+            time::delay(Duration::from_millis(10));
 
             self.wait_serial_ready();
             ptr::write_volatile(UART0_LCR, UART_LCR_DLAB);
@@ -55,6 +60,14 @@ impl Serial {
         }
     }
 
+    /// Raises `SERIAL_IRQ` while there is received data. Reading it all clears the interrupt.
+    pub fn enable_rx_interrupt(&mut self) {
+        self.wait_serial_ready();
+        unsafe {
+            ptr::write_volatile(UART0_IER, UART_IER_ERBFI);
+        }
+    }
+
     fn send_serial(&mut self, chr: u8) {
         self.wait_serial_ready();
         unsafe {
@@ -65,6 +78,17 @@ impl Serial {
     }
 }
 
+impl serial::Read<u8> for Serial {
+    type Error = !;
+
+    fn read(&mut self) -> nb::Result<u8, !> {
+        if unsafe { ptr::read_volatile(UART0_LSR) } & UART_LSR_DR == 0 {
+            return Err(nb::Error::WouldBlock);
+        }
+        Ok(unsafe { ptr::read_volatile(UART0_RBR) } as u8)
+    }
+}
+
 impl serial::Write<u8> for Serial {
     type Error = !;
 
@@ -2,16 +2,49 @@ use core::ptr;
 
 use embedded_hal::serial;
 
+use crate::arch::mmu::phys_to_virt;
+
 pub const BOARD_NAME: &'static str = "QEMU";
 
+/// `(start, size)` of the MMIO windows that are mapped as device memory: GIC and PL011 UART.
+pub const DEVICE_MEMORY: &[(usize, usize)] = &[(0x0800_0000, 0x0200_0000)];
+
+/// Interrupt ID of the PL011 UART (SPI 1).
+pub const SERIAL_IRQ: u32 = 33;
+
 pub struct Serial;
 
-const UART0: *mut u8 = 0x0900_0000 as *mut u8;
+const UART0: *mut u8 = phys_to_virt(0x0900_0000) as *mut u8;
+const UART0_FR: *mut u32 = phys_to_virt(0x0900_0018) as *mut _;
+const UART_FR_RXFE: u32 = 1 << 4;
+const UART0_IMSC: *mut u32 = phys_to_virt(0x0900_0038) as *mut _;
+const UART_IMSC_RXIM: u32 = 1 << 4;
+const UART_IMSC_RTIM: u32 = 1 << 6;
 
 impl Serial {
     pub fn new() -> Self {
         Self
     }
+
+    /// Raises `SERIAL_IRQ` while there is received data. Reading it all clears the interrupt.
+    pub fn enable_rx_interrupt(&mut self) {
+        unsafe {
+            let imsc = ptr::read_volatile(UART0_IMSC);
+            ptr::write_volatile(UART0_IMSC, imsc | UART_IMSC_RXIM | UART_IMSC_RTIM);
+        }
+    }
+}
+
+impl serial::Read<u8> for Serial {
+    type Error = !;
+
+    fn read(&mut self) -> nb::Result<u8, !> {
+        if unsafe { ptr::read_volatile(UART0_FR) } & UART_FR_RXFE != 0 {
+            return Err(nb::Error::WouldBlock);
+        }
+        // The upper bits of DR are error flags.
+        Ok(unsafe { ptr::read_volatile(UART0 as *const u32) } as u8)
+    }
 }
 
 impl serial::Write<u8> for Serial {
@@ -0,0 +1,194 @@
+//! Line-buffered console input.
+//!
+//! Bytes from the serial port are edited into a line as they arrive, in the receive interrupt,
+//! and echoed. Readers get input a line at a time once Enter is pressed. Backspace deletes the
+//! last character, Ctrl-U the whole line, and Ctrl-C throws the line away and interrupts the next
+//! read.
+
+use crate::{
+    serial,
+    sync::{IrqSpinLock, WaitQueue},
+};
+
+/// Longest line that can be entered.
+const LINE_MAX: usize = 256;
+
+/// Bytes of entered lines that have not been read yet.
+const INPUT_SIZE: usize = 1024;
+
+const CTRL_C: u8 = 0x03;
+const BACKSPACE: u8 = 0x08;
+const CTRL_U: u8 = 0x15;
+const DELETE: u8 = 0x7f;
+const BELL: u8 = 0x07;
+
+/// A read was interrupted by Ctrl-C.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Interrupted;
+
+/// A fixed-size FIFO of bytes.
+struct RingBuffer<const N: usize> {
+    data: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    const fn new() -> Self {
+        Self {
+            data: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn free(&self) -> usize {
+        N - self.len
+    }
+
+    /// Appends `byte`. The caller checks that there is room.
+    fn push(&mut self, byte: u8) {
+        debug_assert!(self.len < N);
+        self.data[(self.head + self.len) % N] = byte;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.data[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+struct Input {
+    /// The line being edited.
+    line: [u8; LINE_MAX],
+    line_len: usize,
+    /// Entered lines, each ending with `\n`.
+    lines: RingBuffer<INPUT_SIZE>,
+    /// Set by Ctrl-C until a read sees it.
+    interrupted: bool,
+}
+
+impl Input {
+    /// Removes the last character of the line, with all bytes of its UTF-8 encoding. Returns
+    /// `false` if the line is empty.
+    fn erase_char(&mut self) -> bool {
+        if self.line_len == 0 {
+            return false;
+        }
+        self.line_len -= 1;
+        while self.line_len > 0 && self.line[self.line_len] & 0xc0 == 0x80 {
+            self.line_len -= 1;
+        }
+        true
+    }
+}
+
+static INPUT: IrqSpinLock<Input> = IrqSpinLock::new(Input {
+    line: [0; LINE_MAX],
+    line_len: 0,
+    lines: RingBuffer::new(),
+    interrupted: false,
+});
+
+/// Threads waiting in [`read`].
+static READERS: WaitQueue = WaitQueue::new();
+
+/// Edits the current line with the received `byte`. Called from the serial receive interrupt.
+pub fn receive(byte: u8) {
+    let mut input = INPUT.lock();
+    let mut wake = false;
+    match byte {
+        b'\r' | b'\n' => {
+            if input.lines.free() > input.line_len {
+                for i in 0..input.line_len {
+                    let byte = input.line[i];
+                    input.lines.push(byte);
+                }
+                input.lines.push(b'\n');
+                input.line_len = 0;
+                serial::write_bytes(b"\n");
+                wake = true;
+            } else {
+                // Keep the line until readers have made room.
+                serial::write_bytes(&[BELL]);
+            }
+        }
+        BACKSPACE | DELETE => {
+            if input.erase_char() {
+                serial::write_bytes(b"\x08 \x08");
+            }
+        }
+        CTRL_U => {
+            while input.erase_char() {
+                serial::write_bytes(b"\x08 \x08");
+            }
+        }
+        CTRL_C => {
+            input.line_len = 0;
+            input.interrupted = true;
+            serial::write_bytes(b"^C\n");
+            wake = true;
+        }
+        // Other control characters are ignored.
+        0..=0x1f => {}
+        _ => {
+            if input.line_len < LINE_MAX {
+                let len = input.line_len;
+                input.line[len] = byte;
+                input.line_len += 1;
+                serial::write_bytes(&[byte]);
+            } else {
+                serial::write_bytes(&[BELL]);
+            }
+        }
+    }
+    // Waking takes the wait queue's lock, which readers hold while they take `INPUT`.
+    drop(input);
+    if wake {
+        READERS.wake_all();
+    }
+}
+
+/// Reads up to `buf.len()` bytes of input, sleeping until a line has been entered, and returns
+/// how many it read. A read ends after a `\n`, so it never returns more than one line.
+///
+/// Fails if Ctrl-C was pressed since the last read, or while waiting.
+pub fn read(buf: &mut [u8]) -> Result<usize, Interrupted> {
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    READERS.wait_until(|| {
+        let mut input = INPUT.lock();
+        if input.interrupted {
+            input.interrupted = false;
+            return Some(Err(Interrupted));
+        }
+        if input.lines.is_empty() {
+            return None;
+        }
+        let mut n = 0;
+        while n < buf.len() {
+            match input.lines.pop() {
+                Some(byte) => {
+                    buf[n] = byte;
+                    n += 1;
+                    if byte == b'\n' {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+        Some(Ok(n))
+    })
+}
@@ -0,0 +1,116 @@
+//! Per-CPU data.
+//!
+//! Every core has a [`Cpu`], reachable from the core itself through [`current`]. Index 0 is the
+//! boot core; secondary cores are numbered in the order they appear in the FDT.
+
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use crate::{arch, singleton::Singleton, thread::Thread};
+
+pub const MAX_CPUS: usize = 8;
+
+#[repr(C)]
+pub struct Cpu {
+    /// Initial stack pointer of a secondary core. Read by the secondary entry code in boot.s, so
+    /// it must stay the first field.
+    boot_stack_top: AtomicUsize,
+    /// Hardware identifier (MPIDR affinity on aarch64).
+    hw_id: AtomicU64,
+    present: AtomicBool,
+    online: AtomicBool,
+    /// Set while the core handles an interrupt.
+    pub in_interrupt: AtomicBool,
+
+    // Scheduler state.
+    pub current_thread: AtomicPtr<Thread>,
+    /// The thread switched away from, until the switch has completed.
+    pub prev_thread: AtomicPtr<Thread>,
+    pub idle_thread: AtomicPtr<Thread>,
+    pub ticks_left: AtomicU32,
+    pub need_resched: AtomicBool,
+}
+
+static CPUS: Singleton<[Cpu; MAX_CPUS]> = Singleton::new();
+
+impl Cpu {
+    fn new() -> Self {
+        Self {
+            boot_stack_top: AtomicUsize::new(0),
+            hw_id: AtomicU64::new(0),
+            present: AtomicBool::new(false),
+            online: AtomicBool::new(false),
+            in_interrupt: AtomicBool::new(false),
+            current_thread: AtomicPtr::new(core::ptr::null_mut()),
+            prev_thread: AtomicPtr::new(core::ptr::null_mut()),
+            idle_thread: AtomicPtr::new(core::ptr::null_mut()),
+            ticks_left: AtomicU32::new(0),
+            need_resched: AtomicBool::new(false),
+        }
+    }
+
+    /// Index of this CPU, from 0 to [`MAX_CPUS`].
+    pub fn id(&self) -> usize {
+        let base = CPUS.get().as_ptr();
+        unsafe { (self as *const Cpu).offset_from(base) as usize }
+    }
+
+    pub fn hw_id(&self) -> u64 {
+        self.hw_id.load(Ordering::Relaxed)
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    pub fn set_online(&self) {
+        self.online.store(true, Ordering::Release);
+    }
+
+    pub fn set_offline(&self) {
+        self.online.store(false, Ordering::Release);
+    }
+
+    /// Returns `true` if the CPU has nothing to run but its idle thread.
+    pub fn is_idle(&self) -> bool {
+        let current = self.current_thread.load(Ordering::Relaxed);
+        current == self.idle_thread.load(Ordering::Relaxed)
+    }
+
+    pub fn set_boot_stack_top(&self, top: usize) {
+        self.boot_stack_top.store(top, Ordering::Relaxed);
+    }
+}
+
+/// Sets up the per-CPU data and makes the executing core CPU 0. Must be called first thing on the
+/// boot core.
+pub fn cpu_init() {
+    unsafe {
+        CPUS.init(core::array::from_fn(|_| Cpu::new()));
+    }
+    let boot_cpu = &CPUS.get()[0];
+    boot_cpu.hw_id.store(arch::smp::hw_id(), Ordering::Relaxed);
+    boot_cpu.present.store(true, Ordering::Relaxed);
+    boot_cpu.set_online();
+    arch::smp::set_current_cpu(boot_cpu);
+}
+
+/// Reserves a [`Cpu`] for the secondary core identified by `hw_id`.
+pub fn add_cpu(hw_id: u64) -> Option<&'static Cpu> {
+    let cpu = CPUS
+        .get()
+        .iter()
+        .find(|cpu| !cpu.present.load(Ordering::Relaxed))?;
+    cpu.hw_id.store(hw_id, Ordering::Relaxed);
+    cpu.present.store(true, Ordering::Relaxed);
+    Some(cpu)
+}
+
+/// Returns the executing core's data.
+pub fn current() -> &'static Cpu {
+    arch::smp::current_cpu()
+}
+
+/// Iterates over all CPUs that have been started.
+pub fn online_cpus() -> impl Iterator<Item = &'static Cpu> {
+    CPUS.get().iter().filter(|cpu| cpu.is_online())
+}
@@ -0,0 +1,325 @@
+//! ELF64 loader for user programs.
+//!
+//! Only statically linked AArch64 executables (`ET_EXEC`) are supported. Their `PT_LOAD` segments
+//! are copied into a fresh [`AddressSpace`], and the program starts on a new stack laid out like
+//! Linux does it: `argc` at the stack pointer, followed by `argv`, an empty `envp` and the
+//! auxiliary vector. Segments get the permissions of their flags and must not share pages.
+//!
+//! The image is untrusted: anything malformed is reported as an [`ElfError`].
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use core::fmt;
+
+use crate::{
+    arch::{
+        address_space::{AddressSpace, MapError, USER_END},
+        mmu::{MemoryKind, PAGE_SIZE},
+    },
+    thread::{self, JoinHandle},
+    utils::{align_down, align_up},
+    vfs::FdTable,
+};
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_AARCH64: u16 = 183;
+
+const PHDR_SIZE: usize = 56;
+
+const PT_LOAD: u32 = 1;
+
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+
+// Auxiliary vector entry types.
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+/// Top of the initial user stack.
+const STACK_TOP: usize = 0x8000_0000_0000;
+const STACK_SIZE: usize = 128 * 1024;
+
+/// How much of the stack the arguments may take.
+const MAX_ARGS_SIZE: usize = STACK_SIZE / 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ElfError {
+    /// The image ends in the middle of a header or segment.
+    Truncated,
+    /// The image does not start with the ELF magic.
+    BadMagic,
+    /// Not a little endian ELF64 file of the current version.
+    UnsupportedFormat,
+    /// Not an executable, e.g. a shared object or a relocatable file.
+    NotExecutable(u16),
+    /// Built for another architecture.
+    WrongMachine(u16),
+    /// `e_phentsize` is not the size of an ELF64 program header.
+    BadProgramHeaderSize(u16),
+    /// The `PT_LOAD` segment at this index has `p_filesz > p_memsz` or is not in user space.
+    BadSegment(usize),
+    /// The `PT_LOAD` segment at this index is both writable and executable.
+    WritableAndExecutable(usize),
+    /// `e_entry` is not in an executable segment.
+    BadEntry(u64),
+    /// The arguments do not fit on the initial stack.
+    ArgumentsTooLong,
+    /// The address space could not be set up.
+    Map(MapError),
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::Truncated => write!(f, "truncated image"),
+            ElfError::BadMagic => write!(f, "not an ELF file"),
+            ElfError::UnsupportedFormat => write!(f, "not a little endian ELF64 file"),
+            ElfError::NotExecutable(e_type) => write!(f, "not an executable (type {})", e_type),
+            ElfError::WrongMachine(machine) => write!(f, "wrong machine {}", machine),
+            ElfError::BadProgramHeaderSize(size) => {
+                write!(f, "bad program header size {}", size)
+            }
+            ElfError::BadSegment(i) => write!(f, "bad segment {}", i),
+            ElfError::WritableAndExecutable(i) => {
+                write!(f, "segment {} is writable and executable", i)
+            }
+            ElfError::BadEntry(entry) => write!(f, "entry point {:#x} is not executable", entry),
+            ElfError::ArgumentsTooLong => write!(f, "arguments too long"),
+            ElfError::Map(error) => write!(f, "could not map the program: {}", error),
+        }
+    }
+}
+
+impl From<MapError> for ElfError {
+    fn from(error: MapError) -> Self {
+        ElfError::Map(error)
+    }
+}
+
+fn read<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N], ElfError> {
+    offset
+        .checked_add(N)
+        .and_then(|end| data.get(offset..end))
+        .map(|bytes| bytes.try_into().unwrap())
+        .ok_or(ElfError::Truncated)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, ElfError> {
+    read(data, offset).map(u16::from_le_bytes)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, ElfError> {
+    read(data, offset).map(u32::from_le_bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, ElfError> {
+    read(data, offset).map(u64::from_le_bytes)
+}
+
+/// The parts of the ELF header the loader needs.
+struct ElfHeader {
+    entry: u64,
+    phoff: u64,
+    phnum: u16,
+}
+
+impl ElfHeader {
+    fn parse(image: &[u8]) -> Result<Self, ElfError> {
+        let ident: [u8; 16] = read(image, 0)?;
+        if &ident[..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if ident[4] != ELFCLASS64 || ident[5] != ELFDATA2LSB || ident[6] != EV_CURRENT {
+            return Err(ElfError::UnsupportedFormat);
+        }
+
+        let e_type = read_u16(image, 16)?;
+        if e_type != ET_EXEC {
+            return Err(ElfError::NotExecutable(e_type));
+        }
+        let machine = read_u16(image, 18)?;
+        if machine != EM_AARCH64 {
+            return Err(ElfError::WrongMachine(machine));
+        }
+        let phentsize = read_u16(image, 54)?;
+        if phentsize as usize != PHDR_SIZE {
+            return Err(ElfError::BadProgramHeaderSize(phentsize));
+        }
+
+        Ok(Self {
+            entry: read_u64(image, 24)?,
+            phoff: read_u64(image, 32)?,
+            phnum: read_u16(image, 56)?,
+        })
+    }
+}
+
+/// A program header.
+struct Segment {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+    memsz: u64,
+}
+
+impl Segment {
+    fn parse(image: &[u8], offset: usize) -> Result<Self, ElfError> {
+        let phdr: [u8; PHDR_SIZE] = read(image, offset)?;
+        Ok(Self {
+            kind: read_u32(&phdr, 0)?,
+            flags: read_u32(&phdr, 4)?,
+            offset: read_u64(&phdr, 8)?,
+            vaddr: read_u64(&phdr, 16)?,
+            filesz: read_u64(&phdr, 32)?,
+            memsz: read_u64(&phdr, 40)?,
+        })
+    }
+
+    fn contains(&self, vaddr: u64) -> bool {
+        self.vaddr <= vaddr && vaddr - self.vaddr < self.memsz
+    }
+
+    /// The part of the image that is loaded, or `None` if it is out of bounds.
+    fn data<'a>(&self, image: &'a [u8]) -> Option<&'a [u8]> {
+        let start = usize::try_from(self.offset).ok()?;
+        let end = start.checked_add(usize::try_from(self.filesz).ok()?)?;
+        image.get(start..end)
+    }
+}
+
+fn segments(image: &[u8], header: &ElfHeader) -> Result<Vec<Segment>, ElfError> {
+    let phoff = usize::try_from(header.phoff).map_err(|_| ElfError::Truncated)?;
+    (0..header.phnum as usize)
+        .map(|i| {
+            let offset = i
+                .checked_mul(PHDR_SIZE)
+                .and_then(|offset| offset.checked_add(phoff))
+                .ok_or(ElfError::Truncated)?;
+            Segment::parse(image, offset)
+        })
+        .collect()
+}
+
+/// Maps the `PT_LOAD` segment `index` into `address_space` and copies its contents there.
+fn load_segment(
+    address_space: &AddressSpace,
+    image: &[u8],
+    index: usize,
+    segment: &Segment,
+) -> Result<(), ElfError> {
+    let kind = match segment.flags & (PF_W | PF_X) {
+        flags if flags == PF_W | PF_X => return Err(ElfError::WritableAndExecutable(index)),
+        PF_X => MemoryKind::UserCode,
+        PF_W => MemoryKind::UserData,
+        _ => MemoryKind::UserRodata,
+    };
+
+    let data = segment.data(image).ok_or(ElfError::Truncated)?;
+    let vaddr = segment.vaddr as usize;
+    let end = vaddr.checked_add(segment.memsz as usize);
+    let end = match end {
+        Some(end) if segment.filesz <= segment.memsz && end <= USER_END => end,
+        _ => return Err(ElfError::BadSegment(index)),
+    };
+    if segment.memsz == 0 {
+        return Ok(());
+    }
+
+    let start = align_down(vaddr, PAGE_SIZE);
+    address_space.map_zeroed(start, align_up(end, PAGE_SIZE) - start, kind)?;
+    address_space.write(vaddr, data)?;
+    Ok(())
+}
+
+/// Lays out `argc`, `argv`, an empty `envp` and `auxv` to end at [`STACK_TOP`].
+///
+/// Returns the contents of the stack, which start at the returned stack pointer.
+fn build_stack(args: &[&str], auxv: &[(u64, u64)]) -> Result<(Vec<u8>, usize), ElfError> {
+    let strings_size: usize = args.iter().map(|arg| arg.len() + 1).sum();
+    let words = 1 + (args.len() + 1) + 1 + 2 * (auxv.len() + 1);
+    let size = align_up(strings_size + words * 8, 16);
+    if size > MAX_ARGS_SIZE {
+        return Err(ElfError::ArgumentsTooLong);
+    }
+
+    let sp = STACK_TOP - size;
+    let mut stack = vec![0; size];
+    let mut string_offset = size - strings_size;
+    let mut vector = Vec::with_capacity(words);
+    vector.push(args.len() as u64);
+    for arg in args {
+        vector.push((sp + string_offset) as u64);
+        stack[string_offset..string_offset + arg.len()].copy_from_slice(arg.as_bytes());
+        string_offset += arg.len() + 1;
+    }
+    vector.push(0);
+    vector.push(0);
+    for &(kind, value) in auxv.iter().chain(&[(AT_NULL, 0)]) {
+        vector.push(kind);
+        vector.push(value);
+    }
+
+    for (i, word) in vector.iter().enumerate() {
+        stack[i * 8..(i + 1) * 8].copy_from_slice(&word.to_le_bytes());
+    }
+    Ok((stack, sp))
+}
+
+/// Loads the executable `image` into a new address space and starts it on a new thread, with
+/// `args` as its `argv` and the open files `files`. The handle collects its exit code.
+pub fn spawn(
+    image: &[u8],
+    args: &[&str],
+    files: Arc<FdTable>,
+) -> Result<JoinHandle<i32>, ElfError> {
+    let header = ElfHeader::parse(image)?;
+    let segments = segments(image, &header)?;
+
+    let address_space = AddressSpace::new()?;
+    let mut entry_ok = false;
+    let mut phdr = None;
+    for (i, segment) in segments.iter().enumerate() {
+        if segment.kind != PT_LOAD {
+            continue;
+        }
+        load_segment(&address_space, image, i, segment)?;
+
+        entry_ok |= segment.flags & PF_X != 0 && segment.contains(header.entry);
+        if segment.offset <= header.phoff && header.phoff - segment.offset < segment.filesz {
+            phdr = Some(segment.vaddr + (header.phoff - segment.offset));
+        }
+    }
+    if !entry_ok {
+        return Err(ElfError::BadEntry(header.entry));
+    }
+
+    let mut auxv = vec![
+        (AT_PHENT, PHDR_SIZE as u64),
+        (AT_PHNUM, header.phnum as u64),
+        (AT_PAGESZ, PAGE_SIZE as u64),
+        (AT_ENTRY, header.entry),
+    ];
+    if let Some(phdr) = phdr {
+        auxv.push((AT_PHDR, phdr));
+    }
+    let (stack, sp) = build_stack(args, &auxv)?;
+    address_space.map_zeroed(STACK_TOP - STACK_SIZE, STACK_SIZE, MemoryKind::UserData)?;
+    address_space.write(sp, &stack)?;
+
+    Ok(thread::spawn_user(
+        Arc::new(address_space),
+        files,
+        header.entry as usize,
+        sp,
+        0,
+    ))
+}
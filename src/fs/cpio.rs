@@ -0,0 +1,165 @@
+//! Unpacking of cpio archives in the "newc" format, as used for the initramfs.
+//!
+//! Each member is a 110 byte ASCII header, the NUL terminated path and the file data, with the
+//! header plus path and the data each padded to 4 bytes. A member named `TRAILER!!!` ends the
+//! archive. Several archives may be concatenated, with NUL padding in between.
+//!
+//! Only directories and regular files are unpacked; other members are skipped.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{fmt, str};
+
+use super::tmpfs::Node;
+use crate::{println, utils::align_up, vfs::FsError};
+
+const MAGIC: &[u8] = b"070701";
+const HEADER_SIZE: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpioError {
+    /// The archive ends in the middle of a member.
+    Truncated,
+    /// A member header does not start with the newc magic.
+    BadMagic,
+    /// A header field is not a hexadecimal number.
+    BadHeader,
+    /// A path is not NUL terminated UTF-8.
+    BadName,
+    /// A member could not be added to the filesystem.
+    Fs(FsError),
+}
+
+impl fmt::Display for CpioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpioError::Truncated => write!(f, "truncated archive"),
+            CpioError::BadMagic => write!(f, "not a newc cpio archive"),
+            CpioError::BadHeader => write!(f, "malformed header"),
+            CpioError::BadName => write!(f, "malformed path"),
+            CpioError::Fs(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<FsError> for CpioError {
+    fn from(error: FsError) -> Self {
+        CpioError::Fs(error)
+    }
+}
+
+/// A member of the archive.
+struct Entry<'a> {
+    path: &'a str,
+    mode: u32,
+    data: &'a [u8],
+}
+
+/// Iterates over the members of an archive, up to the last trailer.
+struct CpioIter<'a> {
+    archive: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> CpioIter<'a> {
+    fn bytes(&self, offset: usize, len: usize) -> Result<&'a [u8], CpioError> {
+        offset
+            .checked_add(len)
+            .and_then(|end| self.archive.get(offset..end))
+            .ok_or(CpioError::Truncated)
+    }
+
+    /// Reads the `index`-th 8 digit hexadecimal field of the header at `offset`.
+    fn field(&self, offset: usize, index: usize) -> Result<usize, CpioError> {
+        let digits = self.bytes(offset + MAGIC.len() + index * 8, 8)?;
+        str::from_utf8(digits)
+            .ok()
+            .and_then(|digits| usize::from_str_radix(digits, 16).ok())
+            .ok_or(CpioError::BadHeader)
+    }
+
+    fn parse_entry(&mut self) -> Result<Entry<'a>, CpioError> {
+        let header = self.offset;
+        if self.bytes(header, HEADER_SIZE)?.get(..MAGIC.len()) != Some(MAGIC) {
+            return Err(CpioError::BadMagic);
+        }
+        let mode = self.field(header, 1)? as u32;
+        let file_size = self.field(header, 6)?;
+        let name_size = self.field(header, 11)?;
+
+        let name = self.bytes(header + HEADER_SIZE, name_size)?;
+        let path = match name.split_last() {
+            Some((0, path)) => str::from_utf8(path).map_err(|_| CpioError::BadName)?,
+            _ => return Err(CpioError::BadName),
+        };
+        let data_offset = align_up(header + HEADER_SIZE + name_size, 4);
+        let data = self.bytes(data_offset, file_size)?;
+
+        self.offset = align_up(data_offset + file_size, 4);
+        Ok(Entry { path, mode, data })
+    }
+}
+
+impl<'a> Iterator for CpioIter<'a> {
+    type Item = Result<Entry<'a>, CpioError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // Skip the padding after a trailer.
+            while self.archive.get(self.offset) == Some(&0) {
+                self.offset += 1;
+            }
+            if self.offset >= self.archive.len() {
+                return None;
+            }
+
+            match self.parse_entry() {
+                Ok(entry) if entry.path == TRAILER => continue,
+                Ok(entry) => return Some(Ok(entry)),
+                Err(error) => {
+                    // Stop here rather than misparse the rest.
+                    self.offset = self.archive.len();
+                    return Some(Err(error));
+                }
+            }
+        }
+    }
+}
+
+/// Returns the directory `path` under `root`, creating it and its parents as needed.
+fn create_directories<'a>(
+    root: &Arc<Node>,
+    path: impl Iterator<Item = &'a str>,
+) -> Result<Arc<Node>, FsError> {
+    path.filter(|&name| !name.is_empty() && name != ".")
+        .try_fold(root.clone(), |directory, name| {
+            directory.lookup_or_create_directory(name)
+        })
+}
+
+/// Unpacks `archive` into the directory `root`. Returns the number of files created.
+pub fn unpack(root: &Arc<Node>, archive: &[u8]) -> Result<usize, CpioError> {
+    let mut files = 0;
+    for entry in (CpioIter { archive, offset: 0 }) {
+        let entry = entry?;
+        let mut components: Vec<&str> = entry.path.split('/').collect();
+        let name = components.pop().unwrap_or_default();
+
+        match entry.mode & S_IFMT {
+            S_IFDIR => {
+                create_directories(root, entry.path.split('/'))?;
+            }
+            S_IFREG => {
+                let directory = create_directories(root, components.into_iter())?;
+                directory.insert(name, Node::new_file(entry.data.to_vec()))?;
+                files += 1;
+            }
+            _ => println!("initramfs: skipping {} (mode {:o})", entry.path, entry.mode),
+        }
+    }
+    Ok(files)
+}
@@ -0,0 +1,78 @@
+//! Device nodes, mounted at `/dev`.
+
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+
+use crate::{
+    console, serial,
+    sync::Mutex,
+    vfs::{DirEntry, FsError, Inode, InodeKind},
+};
+
+/// The directory of devices. Drivers add their devices with [`DevFs::add`].
+pub struct DevFs {
+    devices: Mutex<BTreeMap<String, Arc<dyn Inode>>>,
+}
+
+impl DevFs {
+    pub fn new() -> Self {
+        Self {
+            devices: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn add(&self, name: &str, device: Arc<dyn Inode>) -> Result<(), FsError> {
+        let mut devices = self.devices.lock();
+        if devices.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        devices.insert(String::from(name), device);
+        Ok(())
+    }
+}
+
+impl Inode for DevFs {
+    fn kind(&self) -> InodeKind {
+        InodeKind::Directory
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        self.devices
+            .lock()
+            .get(name)
+            .cloned()
+            .ok_or(FsError::NotFound)
+    }
+
+    fn create(&self, _name: &str, _kind: InodeKind) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let devices = self.devices.lock();
+        Ok(devices
+            .iter()
+            .map(|(name, device)| DirEntry {
+                name: name.clone(),
+                kind: device.kind(),
+            })
+            .collect())
+    }
+}
+
+/// The serial console, `/dev/console`. Reads return one line of [`console`] input at a time.
+pub struct Console;
+
+impl Inode for Console {
+    fn kind(&self) -> InodeKind {
+        InodeKind::CharDevice
+    }
+
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        console::read(buf).map_err(|_| FsError::Interrupted)
+    }
+
+    fn write_at(&self, _offset: usize, data: &[u8]) -> Result<usize, FsError> {
+        serial::write_bytes(data);
+        Ok(data.len())
+    }
+}
@@ -0,0 +1,33 @@
+//! Filesystems.
+//!
+//! The root is a [`tmpfs`] filled from the initramfs at boot, with the devices of [`devfs`] at
+//! `/dev`.
+
+pub mod cpio;
+pub mod devfs;
+pub mod tmpfs;
+
+use alloc::sync::Arc;
+
+use crate::{println, vfs};
+use devfs::{Console, DevFs};
+use tmpfs::Node;
+
+/// Mounts the root filesystem, with the initramfs `initrd` unpacked into it if there is one, and
+/// `/dev`.
+pub fn fs_init(initrd: Option<&[u8]>) {
+    let root = Node::new_directory();
+    if let Some(initrd) = initrd {
+        match cpio::unpack(&root, initrd) {
+            Ok(files) => println!("Unpacked {} files from the initramfs.", files),
+            Err(error) => println!("Could not unpack the initramfs: {}", error),
+        }
+    }
+    root.lookup_or_create_directory("dev")
+        .expect("could not create /dev");
+    vfs::mount("/", root).expect("could not mount /");
+
+    let dev = DevFs::new();
+    dev.add("console", Arc::new(Console)).unwrap();
+    vfs::mount("/dev", Arc::new(dev)).expect("could not mount /dev");
+}
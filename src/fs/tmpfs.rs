@@ -0,0 +1,150 @@
+//! An in-memory filesystem.
+//!
+//! Files and directories live on the heap and are gone at shutdown. The root filesystem is a
+//! tmpfs, populated from the initramfs at boot.
+
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+
+use crate::{
+    sync::Mutex,
+    vfs::{DirEntry, FsError, Inode, InodeKind},
+};
+
+enum NodeKind {
+    File(Mutex<Vec<u8>>),
+    Directory(Mutex<BTreeMap<String, Arc<Node>>>),
+}
+
+/// Rejects names that cannot be a directory entry.
+fn check_name(name: &str) -> Result<(), FsError> {
+    match name {
+        "" | "." | ".." => Err(FsError::InvalidPath),
+        _ if name.contains('/') => Err(FsError::InvalidPath),
+        _ => Ok(()),
+    }
+}
+
+/// A file or a directory.
+pub struct Node {
+    kind: NodeKind,
+}
+
+impl Node {
+    pub fn new_file(data: Vec<u8>) -> Arc<Self> {
+        Arc::new(Self {
+            kind: NodeKind::File(Mutex::new(data)),
+        })
+    }
+
+    pub fn new_directory() -> Arc<Self> {
+        Arc::new(Self {
+            kind: NodeKind::Directory(Mutex::new(BTreeMap::new())),
+        })
+    }
+
+    pub fn is_directory(&self) -> bool {
+        matches!(self.kind, NodeKind::Directory(_))
+    }
+
+    fn entries(&self) -> Result<&Mutex<BTreeMap<String, Arc<Node>>>, FsError> {
+        match &self.kind {
+            NodeKind::Directory(entries) => Ok(entries),
+            NodeKind::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn data(&self) -> Result<&Mutex<Vec<u8>>, FsError> {
+        match &self.kind {
+            NodeKind::File(data) => Ok(data),
+            NodeKind::Directory(_) => Err(FsError::IsADirectory),
+        }
+    }
+
+    /// Adds `node` to this directory as `name`.
+    pub fn insert(&self, name: &str, node: Arc<Node>) -> Result<(), FsError> {
+        check_name(name)?;
+        let mut entries = self.entries()?.lock();
+        if entries.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        entries.insert(String::from(name), node);
+        Ok(())
+    }
+
+    /// Returns the directory `name` of this directory, creating it if it does not exist.
+    pub fn lookup_or_create_directory(&self, name: &str) -> Result<Arc<Node>, FsError> {
+        check_name(name)?;
+        let mut entries = self.entries()?.lock();
+        match entries.get(name) {
+            Some(node) if node.is_directory() => Ok(node.clone()),
+            Some(_) => Err(FsError::NotADirectory),
+            None => {
+                let directory = Node::new_directory();
+                entries.insert(String::from(name), directory.clone());
+                Ok(directory)
+            }
+        }
+    }
+}
+
+impl Inode for Node {
+    fn kind(&self) -> InodeKind {
+        match self.kind {
+            NodeKind::File(_) => InodeKind::File,
+            NodeKind::Directory(_) => InodeKind::Directory,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.data().map_or(0, |data| data.lock().len())
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let data = self.data()?.lock();
+        let available = data.get(offset..).unwrap_or(&[]);
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        Ok(n)
+    }
+
+    /// Writing past the end fills the gap with zeros.
+    fn write_at(&self, offset: usize, data: &[u8]) -> Result<usize, FsError> {
+        let mut file = self.data()?.lock();
+        let end = offset
+            .checked_add(data.len())
+            .ok_or(FsError::NotSupported)?;
+        if file.len() < end {
+            file.resize(end, 0);
+        }
+        file[offset..end].copy_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        match self.entries()?.lock().get(name) {
+            Some(node) => Ok(node.clone()),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn create(&self, name: &str, kind: InodeKind) -> Result<Arc<dyn Inode>, FsError> {
+        let node = match kind {
+            InodeKind::File => Node::new_file(Vec::new()),
+            InodeKind::Directory => Node::new_directory(),
+            InodeKind::CharDevice => return Err(FsError::NotSupported),
+        };
+        self.insert(name, node.clone())?;
+        Ok(node)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let entries = self.entries()?.lock();
+        Ok(entries
+            .iter()
+            .map(|(name, node)| DirEntry {
+                name: name.clone(),
+                kind: node.kind(),
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,485 @@
+use crate::{
+    allocator::{self, FrameRange, MAX_ORDER, PAGE_ALLOCATOR, PAGE_SIZE},
+    print, println,
+    sync::IrqSpinLock,
+    utils::{self, align_down, align_up},
+};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem, ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use slab::{SlabCache, SlabStats, SIZE_CLASSES};
+
+mod slab;
+#[cfg(feature = "alloc_tracking")]
+mod tracking;
+
+#[cfg(feature = "alloc_tracking")]
+pub use tracking::print_allocations;
+
+/// The smallest block the heap grows by when it runs out (64KB).
+const GROW_MIN_ORDER: usize = 4;
+
+#[global_allocator]
+static ALLOCATOR: Allocator = Allocator::new();
+
+#[alloc_error_handler]
+fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    println!("allocation of {:?} failed", layout);
+    print_stats();
+    #[cfg(feature = "alloc_tracking")]
+    print_allocations();
+    panic!("allocation error: {:?}", layout)
+}
+
+/// A snapshot of the heap.
+pub struct HeapStats {
+    /// Bytes requested by live allocations.
+    pub in_use: usize,
+    /// The highest `in_use` so far.
+    pub peak: usize,
+    /// Bytes handed to the free-list heap, which serves the allocations too
+    /// large for the slab caches.
+    pub size: usize,
+    pub free: usize,
+    pub free_regions: usize,
+    pub largest_free: usize,
+    pub slabs: [SlabStats; SIZE_CLASSES.len()],
+}
+
+/// Reads the counters and walks the free list.
+pub fn heap_stats() -> HeapStats {
+    let slabs = core::array::from_fn(|class| ALLOCATOR.slabs[class].stats());
+    let inner = ALLOCATOR.inner.lock();
+    let mut stats = HeapStats {
+        in_use: ALLOCATOR.in_use.load(Ordering::Relaxed),
+        peak: ALLOCATOR.peak.load(Ordering::Relaxed),
+        size: inner.size,
+        free: 0,
+        free_regions: 0,
+        largest_free: 0,
+        slabs,
+    };
+    let mut region = inner.head.next.as_deref();
+    while let Some(node) = region {
+        stats.free += node.size;
+        stats.free_regions += 1;
+        stats.largest_free = stats.largest_free.max(node.size);
+        region = node.next.as_deref();
+    }
+    stats
+}
+
+/// Prints the page allocator and heap statistics.
+pub fn print_stats() {
+    let pages = PAGE_ALLOCATOR.get().lock().stats();
+    println!(
+        "pages: {} of {} used ({} KiB each)",
+        pages.used_pages,
+        pages.num_pages,
+        PAGE_SIZE / 1024
+    );
+    print!("  allocated blocks by order:");
+    for count in pages.allocated_blocks {
+        print!(" {}", count);
+    }
+    println!();
+    print!("  free blocks by order:");
+    for count in pages.free_blocks {
+        print!(" {}", count);
+    }
+    println!();
+
+    let heap = heap_stats();
+    println!("heap: {} bytes in use, peak {}", heap.in_use, heap.peak);
+    println!(
+        "  free list: {} of {} bytes free in {} regions, largest {}",
+        heap.free, heap.size, heap.free_regions, heap.largest_free
+    );
+    for slab in heap.slabs {
+        println!(
+            "  {} B slabs: {} frames, {} free objects",
+            slab.object_size, slab.frames, slab.free_objects
+        );
+    }
+}
+
+pub fn heap_init(size: usize) {
+    let size = align_down(size, PAGE_SIZE);
+    println!("Heap init size = {}MB", size / 1024 / 1024);
+    // The heap takes separate blocks, so it can be larger than the largest one.
+    let mut remaining = size;
+    while remaining > 0 {
+        let order = ((remaining / PAGE_SIZE).ilog2() as usize).min(MAX_ORDER);
+        let block = FrameRange::alloc(order).expect("Can't allocate for heap");
+        unsafe {
+            ALLOCATOR.init(block.leak(), PAGE_SIZE << order);
+        }
+        remaining -= PAGE_SIZE << order;
+    }
+}
+
+struct Allocator {
+    /// Small allocations, by size class.
+    slabs: [SlabCache; SIZE_CLASSES.len()],
+    inner: IrqSpinLock<AllocatorInner>,
+    /// Bytes requested by live allocations.
+    in_use: AtomicUsize,
+    /// The highest `in_use` so far.
+    peak: AtomicUsize,
+}
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = match slab::size_class(layout) {
+            Some(class) => self.slabs[class].alloc(),
+            None => self.alloc_large(layout),
+        };
+        if !ptr.is_null() {
+            self.count(0, layout.size());
+            #[cfg(feature = "alloc_tracking")]
+            tracking::on_alloc(ptr, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.count(layout.size(), 0);
+        #[cfg(feature = "alloc_tracking")]
+        tracking::on_dealloc(ptr);
+
+        if let Some(class) = slab::size_class(layout) {
+            return unsafe { self.slabs[class].dealloc(ptr) };
+        }
+
+        // perform layout adjustments
+        let (size, _) = size_align(layout);
+
+        unsafe {
+            self.inner.lock().add_free_region(ptr as usize, size);
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        let in_place = match (slab::size_class(layout), slab::size_class(new_layout)) {
+            (Some(class), Some(new_class)) => class == new_class,
+            (None, None) => {
+                let (size, _) = size_align(layout);
+                let (new_size, _) = size_align(new_layout);
+                unsafe { self.inner.lock().resize(ptr as usize, size, new_size) }
+            }
+            _ => false,
+        };
+        if in_place {
+            self.count(layout.size(), new_size);
+            #[cfg(feature = "alloc_tracking")]
+            tracking::on_resize(ptr, new_size);
+            return ptr;
+        }
+
+        let new_ptr = unsafe { self.alloc(new_layout) };
+        if !new_ptr.is_null() {
+            unsafe {
+                ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+        }
+        new_ptr
+    }
+}
+
+impl Allocator {
+    /// Creates an empty LinkedListAllocator.
+    pub const fn new() -> Self {
+        Self {
+            slabs: [
+                SlabCache::new(SIZE_CLASSES[0]),
+                SlabCache::new(SIZE_CLASSES[1]),
+                SlabCache::new(SIZE_CLASSES[2]),
+                SlabCache::new(SIZE_CLASSES[3]),
+                SlabCache::new(SIZE_CLASSES[4]),
+                SlabCache::new(SIZE_CLASSES[5]),
+                SlabCache::new(SIZE_CLASSES[6]),
+                SlabCache::new(SIZE_CLASSES[7]),
+            ],
+            inner: IrqSpinLock::new(AllocatorInner {
+                head: ListNode::new(0),
+                size: 0,
+            }),
+            in_use: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        }
+    }
+
+    /// Allocates from the free list, growing it if needed.
+    fn alloc_large(&self, layout: Layout) -> *mut u8 {
+        // perform layout adjustments
+        let (size, align) = size_align(layout);
+        let mut inner = self.inner.lock();
+
+        let mut found = inner.find_region(size, align);
+        if found.is_none() && inner.grow(size, align) {
+            found = inner.find_region(size, align);
+        }
+        if let Some((region, alloc_start)) = found {
+            let alloc_end = alloc_start.checked_add(size).expect("overflow");
+            let excess_size = region.end_addr() - alloc_end;
+            let padding = alloc_start - region.start_addr();
+            unsafe {
+                if padding > 0 {
+                    inner.add_free_region(region.start_addr(), padding);
+                }
+                if excess_size > 0 {
+                    inner.add_free_region(alloc_end, excess_size);
+                }
+            }
+            alloc_start as *mut u8
+        } else {
+            ptr::null_mut()
+        }
+    }
+
+    /// Updates the usage counters for an allocation going from `size` to `new_size` bytes.
+    fn count(&self, size: usize, new_size: usize) {
+        if new_size >= size {
+            let grown = new_size - size;
+            let in_use = self.in_use.fetch_add(grown, Ordering::Relaxed) + grown;
+            self.peak.fetch_max(in_use, Ordering::Relaxed);
+        } else {
+            self.in_use.fetch_sub(size - new_size, Ordering::Relaxed);
+        }
+    }
+
+    /// Adds the given heap bounds to the allocator.
+    ///
+    /// This function is unsafe because the caller must guarantee that the given
+    /// heap bounds are valid and that the memory is unused. Each region must be
+    /// added only once.
+    pub unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        let mut inner = self.inner.lock();
+        inner.size += heap_size;
+        unsafe {
+            inner.add_free_region(heap_start, heap_size);
+        }
+    }
+}
+
+/// Adjust the given layout so that the resulting allocated memory
+/// region is also capable of storing a `ListNode`.
+///
+/// Returns the adjusted size and alignment as a (size, align) tuple.
+fn size_align(layout: Layout) -> (usize, usize) {
+    let layout = layout
+        .align_to(mem::align_of::<ListNode>())
+        .expect("adjusting alignment failed")
+        .pad_to_align();
+    let size = layout.size().max(mem::size_of::<ListNode>());
+    (size, layout.align())
+}
+
+struct AllocatorInner {
+    head: ListNode,
+    /// Bytes handed to the allocator.
+    size: usize,
+}
+
+impl AllocatorInner {
+    /// Adds the given memory region to the list, in address order, and merges
+    /// it with the free regions right before and after it.
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        // ensure that the freed region is capable of holding ListNode
+        assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
+        assert!(size >= mem::size_of::<ListNode>());
+
+        // find the last region before the freed one; the head has size 0
+        let mut current = &mut self.head;
+        while current
+            .next
+            .as_ref()
+            .is_some_and(|next| next.start_addr() < addr)
+        {
+            current = current.next.as_mut().unwrap();
+        }
+        debug_assert!(
+            current.size == 0 || current.end_addr() <= addr,
+            "double free of {:#x}",
+            addr
+        );
+
+        let mut node = ListNode::new(size);
+        node.next = current.next.take();
+        if let Some(next) = node.next.as_ref() {
+            debug_assert!(
+                addr + size <= next.start_addr(),
+                "double free of {:#x}",
+                addr
+            );
+        }
+        if node
+            .next
+            .as_ref()
+            .is_some_and(|next| next.start_addr() == addr + size)
+        {
+            let next = node.next.take().unwrap();
+            node.size += next.size;
+            node.next = next.next.take();
+        }
+
+        if current.size != 0 && current.end_addr() == addr {
+            current.size += node.size;
+            current.next = node.next;
+        } else {
+            let node_ptr = addr as *mut ListNode;
+            unsafe {
+                node_ptr.write(node);
+                current.next = Some(&mut *node_ptr)
+            }
+        }
+    }
+
+    /// Adds frames from the page allocator, enough for an allocation with the
+    /// given size and alignment. Returns whether there were any.
+    fn grow(&mut self, size: usize, align: usize) -> bool {
+        let order = allocator::order_for(size + align).max(GROW_MIN_ORDER);
+        match FrameRange::alloc(order) {
+            Some(frames) => {
+                let len = frames.len();
+                self.size += len;
+                unsafe {
+                    self.add_free_region(frames.leak(), len);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Resizes the allocation at `addr` without moving it, by giving back its
+    /// tail or taking the free region right after it.
+    ///
+    /// Returns whether that was possible.
+    unsafe fn resize(&mut self, addr: usize, size: usize, new_size: usize) -> bool {
+        if new_size <= size {
+            let excess_size = size - new_size;
+            if excess_size == 0 {
+                return true;
+            }
+            if excess_size < mem::size_of::<ListNode>() {
+                return false;
+            }
+            unsafe {
+                self.add_free_region(addr + new_size, excess_size);
+            }
+            return true;
+        }
+
+        let end = addr + size;
+        let needed = new_size - size;
+        let mut current = &mut self.head;
+        while current
+            .next
+            .as_ref()
+            .is_some_and(|next| next.start_addr() < end)
+        {
+            current = current.next.as_mut().unwrap();
+        }
+        let next_size = match current.next.as_ref() {
+            Some(next) if next.start_addr() == end => next.size,
+            _ => return false,
+        };
+        let excess_size = match next_size.checked_sub(needed) {
+            Some(excess) if excess == 0 || excess >= mem::size_of::<ListNode>() => excess,
+            _ => return false,
+        };
+
+        let after = current.next.take().unwrap().next.take();
+        if excess_size == 0 {
+            current.next = after;
+        } else {
+            let mut node = ListNode::new(excess_size);
+            node.next = after;
+            let node_ptr = (end + needed) as *mut ListNode;
+            unsafe {
+                node_ptr.write(node);
+                current.next = Some(&mut *node_ptr)
+            }
+        }
+        true
+    }
+
+    /// Looks for a free region with the given size and alignment and removes
+    /// it from the list.
+    ///
+    /// Returns a tuple of the list node and the start address of the allocation.
+    fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
+        // reference to current list node, updated for each iteration
+        let mut current = &mut self.head;
+        // look for a large enough memory region in linked list
+        while let Some(ref mut region) = current.next {
+            if let Ok(alloc_start) = Self::alloc_from_region(&region, size, align) {
+                // region suitable for allocation -> remove node from list
+                let next = region.next.take();
+                let ret = Some((current.next.take().unwrap(), alloc_start));
+                current.next = next;
+                return ret;
+            } else {
+                // region not suitable -> continue with next region
+                current = current.next.as_mut().unwrap();
+            }
+        }
+
+        // no suitable region found
+        None
+    }
+
+    /// Try to use the given region for an allocation with given size and
+    /// alignment.
+    ///
+    /// Returns the allocation start address on success.
+    fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
+        let mut alloc_start = align_up(region.start_addr(), align);
+        let padding = alloc_start - region.start_addr();
+        if padding > 0 && padding < mem::size_of::<ListNode>() {
+            // the padding goes back to the list, so it must hold a ListNode
+            alloc_start = align_up(region.start_addr() + mem::size_of::<ListNode>(), align);
+        }
+        let alloc_end = alloc_start.checked_add(size).ok_or(())?;
+
+        if alloc_end > region.end_addr() {
+            // region too small
+            return Err(());
+        }
+
+        let excess_size = region.end_addr() - alloc_end;
+        if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+            // rest of region too small to hold a ListNode (required because the
+            // allocation splits the region in a used and a free part)
+            return Err(());
+        }
+
+        // region suitable for allocation
+        Ok(alloc_start)
+    }
+}
+
+struct ListNode {
+    size: usize,
+    next: Option<&'static mut ListNode>,
+}
+
+impl ListNode {
+    const fn new(size: usize) -> Self {
+        ListNode { size, next: None }
+    }
+
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
+}
@@ -0,0 +1,111 @@
+//! Caches of fixed-size objects for small allocations.
+//!
+//! Each cache carves frames from the page allocator into objects of one size class, and keeps the
+//! free ones on a list threaded through the objects themselves, so allocating and freeing are
+//! O(1). Objects are aligned to their size. Frames taken by a cache are never given back, but their
+//! objects are reused by later allocations of the same class.
+
+use core::{alloc::Layout, ptr::NonNull};
+
+use crate::{
+    allocator::{FrameRange, PAGE_SIZE},
+    sync::IrqSpinLock,
+};
+
+/// Object sizes of the caches. Larger allocations go to the free-list heap.
+pub const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Returns the index of the cache that serves `layout`, if any.
+pub fn size_class(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|&class| class >= size)
+}
+
+/// A snapshot of a cache.
+pub struct SlabStats {
+    pub object_size: usize,
+    /// Frames taken from the page allocator.
+    pub frames: usize,
+    pub free_objects: usize,
+}
+
+pub struct SlabCache {
+    object_size: usize,
+    free: IrqSpinLock<FreeList>,
+}
+
+struct FreeList {
+    head: Option<NonNull<FreeObject>>,
+    len: usize,
+    frames: usize,
+}
+
+// The list only points into frames owned by the cache.
+unsafe impl Send for FreeList {}
+
+/// Written at the start of every free object.
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+impl SlabCache {
+    pub const fn new(object_size: usize) -> Self {
+        Self {
+            object_size,
+            free: IrqSpinLock::new(FreeList {
+                head: None,
+                len: 0,
+                frames: 0,
+            }),
+        }
+    }
+
+    /// Returns a free object, or null if there is none and no frame to make more from.
+    pub fn alloc(&self) -> *mut u8 {
+        let mut free = self.free.lock();
+        if free.head.is_none() {
+            match FrameRange::alloc(0) {
+                Some(frame) => self.add_frame(&mut free, frame.leak()),
+                None => return core::ptr::null_mut(),
+            }
+        }
+        let object = free.head.unwrap();
+        free.head = unsafe { (*object.as_ptr()).next };
+        free.len -= 1;
+        object.as_ptr() as *mut u8
+    }
+
+    /// Returns an object to the cache.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from [`alloc`](Self::alloc) on the same cache and not be used after this.
+    pub unsafe fn dealloc(&self, ptr: *mut u8) {
+        let mut free = self.free.lock();
+        unsafe { Self::push(&mut free, ptr) };
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        let free = self.free.lock();
+        SlabStats {
+            object_size: self.object_size,
+            frames: free.frames,
+            free_objects: free.len,
+        }
+    }
+
+    /// Splits a new frame into free objects.
+    fn add_frame(&self, free: &mut FreeList, frame: usize) {
+        free.frames += 1;
+        for object in (frame..frame + PAGE_SIZE).step_by(self.object_size).rev() {
+            unsafe { Self::push(free, object as *mut u8) };
+        }
+    }
+
+    unsafe fn push(free: &mut FreeList, ptr: *mut u8) {
+        let object = ptr as *mut FreeObject;
+        unsafe { object.write(FreeObject { next: free.head }) };
+        free.head = NonNull::new(object);
+        free.len += 1;
+    }
+}
@@ -0,0 +1,93 @@
+//! Records where the live heap allocations were made, with the `alloc_tracking` feature.
+//!
+//! The records are kept in a fixed table, since they cannot come from the heap they describe.
+//! Allocations made while it is full are only counted. Looking a pointer up is a linear scan, which
+//! is fine for debugging but not for normal use.
+
+use crate::{arch::backtrace, print, println, sync::IrqSpinLock};
+
+/// Allocations that can be tracked at once.
+const MAX_TRACKED: usize = 1024;
+
+/// Return addresses kept for each allocation, innermost first.
+const CALLERS: usize = 8;
+
+#[derive(Clone, Copy)]
+struct Record {
+    /// Null for an unused slot.
+    ptr: usize,
+    size: usize,
+    callers: [usize; CALLERS],
+}
+
+const UNUSED: Record = Record {
+    ptr: 0,
+    size: 0,
+    callers: [0; CALLERS],
+};
+
+struct Table {
+    records: [Record; MAX_TRACKED],
+    /// Live allocations without a record.
+    untracked: usize,
+}
+
+impl Table {
+    /// Returns the record of `ptr`, or an unused one for null.
+    fn find(&mut self, ptr: usize) -> Option<&mut Record> {
+        self.records.iter_mut().find(|record| record.ptr == ptr)
+    }
+}
+
+static TABLE: IrqSpinLock<Table> = IrqSpinLock::new(Table {
+    records: [UNUSED; MAX_TRACKED],
+    untracked: 0,
+});
+
+pub fn on_alloc(ptr: *mut u8, size: usize) {
+    let mut callers = [0; CALLERS];
+    backtrace::return_addresses(&mut callers);
+
+    let mut table = TABLE.lock();
+    match table.find(0) {
+        Some(record) => {
+            *record = Record {
+                ptr: ptr as usize,
+                size,
+                callers,
+            }
+        }
+        None => table.untracked += 1,
+    }
+}
+
+pub fn on_dealloc(ptr: *mut u8) {
+    let mut table = TABLE.lock();
+    match table.find(ptr as usize) {
+        Some(record) => *record = UNUSED,
+        None => table.untracked -= 1,
+    }
+}
+
+/// Updates the size of an allocation that was resized in place.
+pub fn on_resize(ptr: *mut u8, new_size: usize) {
+    let mut table = TABLE.lock();
+    if let Some(record) = table.find(ptr as usize) {
+        record.size = new_size;
+    }
+}
+
+/// Prints every tracked allocation with its callers.
+pub fn print_allocations() {
+    let table = TABLE.lock();
+    for record in table.records.iter().filter(|record| record.ptr != 0) {
+        print!("{:#x} {} bytes from", record.ptr, record.size);
+        for &caller in record.callers.iter().take_while(|&&caller| caller != 0) {
+            print!(" {:#x}", caller);
+        }
+        println!();
+    }
+    if table.untracked > 0 {
+        println!("{} more allocations not tracked", table.untracked);
+    }
+}
@@ -0,0 +1,90 @@
+//! Inter-processor interrupts.
+//!
+//! Each [`Ipi`] message is delivered as its own software generated interrupt, so messages of
+//! different kinds never overwrite each other.
+
+use core::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    arch::irq,
+    cpu::{self, Cpu},
+    time,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Ipi {
+    /// Reschedule at the end of the interrupt, e.g. to pick up newly queued threads.
+    Reschedule = 0,
+    /// Stop the CPU for good. Sent by the panic handler.
+    Halt = 1,
+}
+
+const IPIS: [Ipi; 2] = [Ipi::Reschedule, Ipi::Halt];
+
+/// How long a panicking CPU waits for the others to stop.
+const HALT_TIMEOUT: Duration = Duration::from_millis(100);
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+fn handle_ipi(irq: u32) {
+    let cpu = cpu::current();
+    match IPIS.get(irq as usize) {
+        Some(Ipi::Reschedule) => cpu.need_resched.store(true, Ordering::Relaxed),
+        Some(Ipi::Halt) => stop_this_cpu(),
+        None => unreachable!("IPI handler installed for interrupt {}", irq),
+    }
+}
+
+/// Installs the IPI handlers. Must be called on the boot core before secondary cores are started.
+pub fn ipi_init() {
+    for ipi in IPIS {
+        irq::register_handler(ipi as u32, handle_ipi);
+    }
+}
+
+/// Enables IPIs on a secondary core.
+pub fn ipi_init_cpu() {
+    for ipi in IPIS {
+        irq::enable_irq(ipi as u32);
+    }
+}
+
+pub fn send(target: &Cpu, ipi: Ipi) {
+    irq::send_sgi(ipi as u32, target);
+}
+
+/// Sends `ipi` to all online CPUs except the executing one.
+pub fn send_to_others(ipi: Ipi) {
+    let this_cpu = cpu::current().id();
+    for cpu in cpu::online_cpus().filter(|cpu| cpu.id() != this_cpu) {
+        send(cpu, ipi);
+    }
+}
+
+/// Stops all other CPUs, so that a panic message is not interleaved with their output.
+///
+/// If another CPU is already panicking, stops the executing one instead.
+pub fn halt_others() {
+    irq::local_irq_disable();
+    if PANICKING.swap(true, Ordering::Relaxed) {
+        stop_this_cpu();
+    }
+
+    send_to_others(Ipi::Halt);
+    let deadline = time::now() + HALT_TIMEOUT;
+    while cpu::online_cpus().count() > 1 && time::now() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+fn stop_this_cpu() -> ! {
+    irq::local_irq_disable();
+    cpu::current().set_offline();
+    loop {
+        cortex_a::asm::wfe();
+    }
+}
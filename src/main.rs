@@ -11,12 +11,22 @@
 
 
 mod allocator;
+mod console;
+mod cpu;
+mod elf;
+mod fs;
 mod heap;
+mod ipi;
 mod serial;
+#[cfg(feature = "debug_shell")]
+mod shell;
 mod singleton;
 mod sync;
+mod syscall;
 mod thread;
+mod time;
 mod utils;
+mod vfs;
 
 #[cfg(target_arch = "aarch64")]
 #[path = "arch/aarch64/mod.rs"]
@@ -29,7 +39,7 @@ pub use core;
 #[doc(inline)]
 pub extern crate alloc;
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, sync::Arc};
 use core::panic::PanicInfo;
 
 use crate::allocator::PAGE_SIZE;
@@ -38,60 +48,80 @@ use crate::thread::{Thread, SCHEDULER};
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    ipi::halt_others();
     println!("{}", info);
     arch::system_off();
 }
 
-fn init_bss() {
-    println!("Initialize bss");
-    extern "Rust" {
-        static __EXT_BSS_START: ();
-        static __EXT_BSS_END_INCLUSIVE: ();
-    }
-    unsafe {
-        let start = &__EXT_BSS_START as *const () as *mut u8;
-        let end = &__EXT_BSS_START as *const () as *mut u8;
-        let buf = core::slice::from_raw_parts_mut(start, end.offset_from(start) as usize);
-        for byte in buf {
-            *byte = 0;
-        }
+fn thread1() -> usize {
+    for _ in 0..10 {
+        println!("Hello from thread #1 on CPU {}", cpu::current().id());
+        Thread::sleep(time::Duration::from_millis(100));
     }
+    1
 }
 
-fn thread1() {
+fn thread2() -> usize {
     for _ in 0..10 {
-        println!("Hello from thread #1");
+        println!("Hello from thread #2 on CPU {}", cpu::current().id());
         Thread::yield_current();
     }
+    2
 }
 
-fn thread2() {
-    for _ in 0..9 {
-        println!("Hello from thread #2");
-        Thread::yield_current();
-    }
+/// Sums what a thread sends over a channel too small to hold all of it at once.
+fn channel_demo() -> u32 {
+    let (sender, receiver) = sync::channel(4);
+    let producer = thread::spawn(move || {
+        for i in 1..=10 {
+            sender.send(i).unwrap();
+        }
+    });
+    let sum = core::iter::from_fn(|| receiver.recv().ok()).sum();
+    producer.join();
+    sum
+}
 
-    println!("Hello from thread #2");
-    arch::system_off()
+/// Runs `/init` from the initramfs at EL0, or the demo program from user.s if there is none, and
+/// returns its exit code. Its standard input and output are the console.
+fn user_demo() -> i32 {
+    let console = vfs::open(
+        "/dev/console",
+        vfs::OpenOptions {
+            read: true,
+            write: true,
+            create: false,
+        },
+    )
+    .expect("could not open the console");
+    let files = Arc::new(vfs::FdTable::with_stdio(console));
+    let handle = match vfs::read_file("/init") {
+        Ok(image) => elf::spawn(&image, &["/init"], files),
+        Err(_) => elf::spawn(arch::user::demo_program(), &["user-demo"], files),
+    };
+    handle.expect("could not load the user demo").join()
 }
 
-fn init() {
+fn init(initrd: Option<&'static [u8]>) {
     println!("Hello from init");
-    let thread1 = Box::leak(Box::new(Thread::new(thread1))) as *mut _;
-    let thread2 = Box::leak(Box::new(Thread::new(thread2))) as *mut _;
-    {
-        let mut scheduler = SCHEDULER.get().lock();
-        scheduler.add(thread1);
-        scheduler.add(thread2);
-    }
-
-    loop {
-        Thread::yield_current();
-    }
+    fs::fs_init(initrd);
+    let thread1 = thread::spawn(thread1);
+    let thread2 = thread::spawn(thread2);
+    println!("thread #2 returned {}", thread2.join());
+    println!("thread #1 returned {}", thread1.join());
+    println!("channel demo received {}", channel_demo());
+    println!("user demo exited with {}", user_demo());
+
+    // The shell powers off when asked to.
+    #[cfg(feature = "debug_shell")]
+    thread::spawn(shell::run);
+    #[cfg(not(feature = "debug_shell"))]
+    arch::system_off()
 }
 
 fn idle() {
     loop {
+        thread::reap_dead_threads();
         cortex_a::asm::wfi();
         Thread::yield_current();
     }
@@ -111,53 +141,86 @@ pub extern "C" fn _main() {
 
 #[cfg(test)]
 pub fn test_main() {
-    init_bss();
     test_harness_main();
     arch::system_off();
 }
 
 pub fn main() {
-    use spin::mutex::SpinMutex;
     use tock_registers::interfaces::Readable;
 
+    cpu::cpu_init();
     serial_init();
 
     println!("Hello from {}!", bsp::BOARD_NAME);
     println!(
-        "Currently running in EL {}",
+        "Entered at EL {}, currently running in EL {}",
+        arch::boot::boot_el(),
         cortex_a::registers::CurrentEL.read(cortex_a::registers::CurrentEL::EL)
     );
 
-    loop {}
-    //init_bss();
-    //unsafe {
-    //arch::fdt::fdt_init();
-    //}
-    //unsafe {
-    //arch::exception::handling_init();
-    //}
-    //let (start, size) = arch::fdt::fdt_get_memory();
-    //let end = start + size;
-    //extern "Rust" {
-    //static __EXT_STACK_END: ();
-    //}
-    //let heap_start = utils::align_up(unsafe { &__EXT_STACK_END } as *const () as usize, PAGE_SIZE);
-    //unsafe {
-    //allocator::page_allocator_init(heap_start, end as usize - heap_start);
-    //}
-    //println!("OK");
-
-    //heap::heap_init(size as usize / 16);
-
-    //let idle_thread = Box::leak(Box::try_new(Thread::new(idle)).unwrap()) as *mut _;
-    //let init_thread = Box::leak(Box::try_new(Thread::new(init)).unwrap()) as *mut _;
-
-    //unsafe {
-    //use crate::thread::Scheduler;
-
-    //SCHEDULER.init(SpinMutex::new(Scheduler::new(idle_thread)));
-    //Thread::start_with(init_thread);
-    //}
+    unsafe {
+        arch::fdt::fdt_init();
+    }
+    unsafe {
+        arch::exception::handling_init();
+        arch::psci::psci_init();
+    }
+    let (start, size) = arch::fdt::fdt_get_memory();
+    let initrd = arch::fdt::fdt_get_initrd();
+    let end = arch::mmu::phys_to_virt((start + size) as usize);
+    unsafe {
+        arch::mmu::mmu_init((start as usize, size as usize));
+    }
+    arch::irq::irq_init();
+    arch::timer::timer_init();
+    serial::serial_rx_init();
+    ipi::ipi_init();
+
+    extern "Rust" {
+        static __EXT_STACK_END: ();
+    }
+    let heap_start = utils::align_up(unsafe { &__EXT_STACK_END } as *const () as usize, PAGE_SIZE);
+    // The FDT and the initramfs stay where the bootloader put them, and are never freed.
+    let to_virt = |(start, end)| (arch::mmu::phys_to_virt(start), arch::mmu::phys_to_virt(end));
+    let fdt = to_virt(arch::fdt::fdt_get_blob());
+    let initrd = initrd.map(to_virt);
+    unsafe {
+        let reserved = [fdt, initrd.unwrap_or_default()];
+        allocator::page_allocator_init(heap_start, end - heap_start, &reserved);
+    }
+    let initrd = initrd.map(|(start, end)| unsafe {
+        core::slice::from_raw_parts(start as *const u8, end - start)
+    });
+
+    heap::heap_init(size as usize / 16);
+    arch::user::user_init();
+
+    let idle_thread = Box::leak(Box::try_new(Thread::new(idle)).unwrap()) as *mut _;
+    let init_thread = Box::leak(Box::try_new(Thread::new(move || init(initrd))).unwrap()) as *mut _;
+
+    unsafe {
+        use crate::thread::Scheduler;
+
+        SCHEDULER.init(Scheduler::new());
+    }
+    cpu::current()
+        .idle_thread
+        .store(idle_thread, core::sync::atomic::Ordering::Relaxed);
+
+    arch::smp::smp_init();
+    println!("{} CPUs online", cpu::online_cpus().count());
+
+    Thread::start_with(init_thread);
+}
+
+/// Entry point of secondary cores, once their architecture specific setup is done.
+pub fn secondary_main() -> ! {
+    let cpu = cpu::current();
+    let idle_thread = Box::leak(Box::try_new(Thread::new(idle)).unwrap()) as *mut _;
+    cpu.idle_thread
+        .store(idle_thread, core::sync::atomic::Ordering::Relaxed);
+    cpu.set_online();
+    Thread::start_with(idle_thread);
 }
 
 #[cfg(test)]
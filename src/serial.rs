@@ -1,9 +1,12 @@
 use crate::bsp;
-use crate::{singleton::Singleton, sync::SpinMutex};
+use crate::{arch::irq, console, singleton::Singleton, sync::IrqSpinLock};
 use core::fmt::{self, Write};
 use core::ptr;
 
-static_assertions::assert_impl_all!(bsp::Serial: embedded_hal::serial::Write<u8>);
+static_assertions::assert_impl_all!(
+    bsp::Serial: embedded_hal::serial::Read<u8>,
+    embedded_hal::serial::Write<u8>
+);
 
 struct Serial {
     bsp: bsp::Serial,
@@ -15,10 +18,36 @@ impl Serial {
     }
 }
 
-static SERIAL: Singleton<SpinMutex<Serial>> = Singleton::new();
+static SERIAL: Singleton<IrqSpinLock<Serial>> = Singleton::new();
 
 pub fn serial_init() {
-    unsafe { SERIAL.init(SpinMutex::new(Serial::new())); }
+    unsafe { SERIAL.init(IrqSpinLock::new(Serial::new())); }
+}
+
+/// Hands received bytes to the console from now on. Needs the interrupt controller.
+pub fn serial_rx_init() {
+    SERIAL.get().lock().bsp.enable_rx_interrupt();
+    irq::register_handler(bsp::SERIAL_IRQ, handle_rx);
+}
+
+fn handle_rx(_irq: u32) {
+    // The console echoes through the serial port, so empty the FIFO before calling it. Anything
+    // left over raises the interrupt again.
+    let mut received = [0; 64];
+    let mut len = 0;
+    {
+        let mut serial = SERIAL.get().lock();
+        while len < received.len() {
+            match embedded_hal::serial::Read::read(&mut serial.bsp) {
+                Ok(byte) => received[len] = byte,
+                Err(_) => break,
+            }
+            len += 1;
+        }
+    }
+    for &byte in &received[..len] {
+        console::receive(byte);
+    }
 }
 
 #[doc(hidden)]
@@ -29,6 +58,14 @@ pub fn _print(args: fmt::Arguments) {
         .unwrap();
 }
 
+/// Writes raw bytes, which need not be valid UTF-8.
+pub fn write_bytes(bytes: &[u8]) {
+    let mut serial = SERIAL.get().lock();
+    for &byte in bytes {
+        nb::block!(embedded_hal::serial::Write::write(&mut serial.bsp, byte)).unwrap();
+    }
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => ($crate::serial::_print(format_args!($($arg)*)));
@@ -0,0 +1,204 @@
+//! A kernel monitor on the serial console, for debugging.
+//!
+//! Built with the `debug_shell` feature. It runs as a kernel thread once `init` is done, reads
+//! commands a line at a time from the [`console`](crate::console) and trusts whoever types them:
+//! `peek` and `poke` reach any physical address in the linear map. An unmapped one is reported,
+//! but writing the wrong word can still take the kernel down.
+
+use core::str;
+
+use crate::{
+    arch::{self, fdt, mmu::phys_to_virt, probe},
+    console, heap, print, println, thread,
+};
+
+/// End of the physical addresses `peek` and `poke` accept: the part covered by the linear map.
+const PHYS_LIMIT: usize = 4 << 30;
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    run: fn(args: &mut str::SplitWhitespace) -> Result<(), &'static str>,
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "help: list the commands",
+        run: help,
+    },
+    Command {
+        name: "mem",
+        usage: "mem: page allocator and heap usage",
+        run: mem,
+    },
+    #[cfg(feature = "alloc_tracking")]
+    Command {
+        name: "allocs",
+        usage: "allocs: live heap allocations and their callers",
+        run: allocs,
+    },
+    Command {
+        name: "threads",
+        usage: "threads: running and queued threads",
+        run: threads,
+    },
+    Command {
+        name: "fdt",
+        usage: "fdt: print the device tree",
+        run: print_fdt,
+    },
+    Command {
+        name: "peek",
+        usage: "peek <phys addr> [words]: read 32-bit words",
+        run: peek,
+    },
+    Command {
+        name: "poke",
+        usage: "poke <phys addr> <value>: write a 32-bit word",
+        run: poke,
+    },
+    Command {
+        name: "reboot",
+        usage: "reboot: reset the system",
+        run: reboot,
+    },
+    Command {
+        name: "poweroff",
+        usage: "poweroff: power the system off",
+        run: poweroff,
+    },
+];
+
+/// Reads and runs commands forever.
+pub fn run() {
+    probe::probe_init();
+    println!("Debug shell, type `help` for the commands.");
+    let mut buf = [0; 256];
+    loop {
+        print!("> ");
+        let len = match console::read(&mut buf) {
+            Ok(len) => len,
+            // Ctrl-C has already been echoed; start over.
+            Err(console::Interrupted) => continue,
+        };
+        let line = match str::from_utf8(&buf[..len]) {
+            Ok(line) => line,
+            Err(_) => {
+                println!("not UTF-8");
+                continue;
+            }
+        };
+
+        let mut args = line.split_whitespace();
+        let name = match args.next() {
+            Some(name) => name,
+            None => continue,
+        };
+        match COMMANDS.iter().find(|command| command.name == name) {
+            Some(command) => {
+                if let Err(error) = (command.run)(&mut args) {
+                    println!("{}", error);
+                    println!("usage: {}", command.usage);
+                }
+            }
+            None => println!("unknown command `{}`", name),
+        }
+    }
+}
+
+/// Parses a number in decimal, or in hexadecimal with a `0x` prefix.
+fn parse_number(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Parses a 4-byte aligned physical address in the linear map.
+fn parse_phys_addr(s: Option<&str>) -> Result<usize, &'static str> {
+    let addr = s.and_then(parse_number).ok_or("missing or bad address")?;
+    if addr % 4 != 0 {
+        return Err("address not 4-byte aligned");
+    }
+    if addr >= PHYS_LIMIT {
+        return Err("address not in the linear map");
+    }
+    Ok(addr)
+}
+
+fn help(_args: &mut str::SplitWhitespace) -> Result<(), &'static str> {
+    for command in COMMANDS {
+        println!("  {}", command.usage);
+    }
+    Ok(())
+}
+
+fn mem(_args: &mut str::SplitWhitespace) -> Result<(), &'static str> {
+    heap::print_stats();
+    Ok(())
+}
+
+#[cfg(feature = "alloc_tracking")]
+fn allocs(_args: &mut str::SplitWhitespace) -> Result<(), &'static str> {
+    heap::print_allocations();
+    Ok(())
+}
+
+fn threads(_args: &mut str::SplitWhitespace) -> Result<(), &'static str> {
+    thread::dump_threads();
+    Ok(())
+}
+
+fn print_fdt(_args: &mut str::SplitWhitespace) -> Result<(), &'static str> {
+    fdt::fdt_print();
+    Ok(())
+}
+
+fn peek(args: &mut str::SplitWhitespace) -> Result<(), &'static str> {
+    let addr = parse_phys_addr(args.next())?;
+    let words = match args.next() {
+        Some(words) => parse_number(words).ok_or("bad word count")?,
+        None => 1,
+    };
+    let words = words.min((PHYS_LIMIT - addr) / 4);
+
+    for i in 0..words {
+        let addr = addr + i * 4;
+        if i % 4 == 0 {
+            if i != 0 {
+                println!();
+            }
+            print!("{:#010x}:", addr);
+        }
+        match probe::read_u32(phys_to_virt(addr)) {
+            Ok(value) => print!(" {:08x}", value),
+            Err(probe::ProbeFault) => {
+                println!();
+                return Err("address not mapped");
+            }
+        }
+    }
+    println!();
+    Ok(())
+}
+
+fn poke(args: &mut str::SplitWhitespace) -> Result<(), &'static str> {
+    let addr = parse_phys_addr(args.next())?;
+    let value = args
+        .next()
+        .and_then(parse_number)
+        .and_then(|value| u32::try_from(value).ok())
+        .ok_or("missing or bad 32-bit value")?;
+    unsafe { probe::write_u32(phys_to_virt(addr), value) }.map_err(|_| "address not mapped")
+}
+
+fn reboot(_args: &mut str::SplitWhitespace) -> Result<(), &'static str> {
+    let error = arch::system_reset();
+    println!("reset failed: {:?}", error);
+    Ok(())
+}
+
+fn poweroff(_args: &mut str::SplitWhitespace) -> Result<(), &'static str> {
+    arch::system_off()
+}
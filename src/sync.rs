@@ -1 +0,0 @@
-pub use spin::mutex::spin::SpinMutex;
@@ -0,0 +1,181 @@
+use alloc::{collections::VecDeque, sync::Arc};
+use core::mem;
+
+use super::{IrqSpinLock, WaitQueue};
+
+struct State<T> {
+    buffer: VecDeque<T>,
+    capacity: usize,
+    senders: usize,
+    receiver: bool,
+}
+
+struct Shared<T> {
+    state: IrqSpinLock<State<T>>,
+    /// The receiver, waiting for values or for the last sender to go.
+    readers: WaitQueue,
+    /// Senders waiting for space or for the receiver to go.
+    writers: WaitQueue,
+}
+
+impl<T> Shared<T> {
+    /// Blocks on `queue` until `f` returns `Some` for the locked state, and returns its value.
+    fn wait<R>(&self, queue: &WaitQueue, mut f: impl FnMut(&mut State<T>) -> Option<R>) -> R {
+        queue.wait_until(|| f(&mut self.state.lock()))
+    }
+}
+
+/// Creates a channel that buffers up to `capacity` values.
+///
+/// Sending blocks while the channel is full and receiving while it is empty. Once all senders are
+/// gone the receiver gets what is left and then [`RecvError`]; once the receiver is gone sending
+/// fails.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel without capacity");
+    let shared = Arc::new(Shared {
+        state: IrqSpinLock::new(State {
+            buffer: VecDeque::with_capacity(capacity),
+            capacity,
+            senders: 1,
+            receiver: true,
+        }),
+        readers: WaitQueue::new(),
+        writers: WaitQueue::new(),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+/// The receiver is gone. Holds what could not be sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+/// All senders are gone and the channel is empty.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecvError;
+
+/// The sending half of a [`channel`]. Clone it to send from several threads.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends `value`, sleeping while the channel is full.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut value = Some(value);
+        self.shared.wait(&self.shared.writers, |state| {
+            if !state.receiver {
+                return Some(Err(SendError(value.take().unwrap())));
+            }
+            if state.buffer.len() == state.capacity {
+                return None;
+            }
+            state.buffer.push_back(value.take().unwrap());
+            Some(Ok(()))
+        })?;
+        self.shared.readers.wake_one();
+        Ok(())
+    }
+}
+
+impl<T: Copy> Sender<T> {
+    /// Sends as much of `data` as fits, sleeping until at least one value does, and returns how
+    /// many values were sent.
+    pub fn send_slice(&self, data: &[T]) -> Result<usize, SendError<()>> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let n = self.shared.wait(&self.shared.writers, |state| {
+            if !state.receiver {
+                return Some(Err(SendError(())));
+            }
+            let n = data.len().min(state.capacity - state.buffer.len());
+            if n == 0 {
+                return None;
+            }
+            state.buffer.extend(&data[..n]);
+            Some(Ok(n))
+        })?;
+        self.shared.readers.wake_one();
+        Ok(n)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock();
+        state.senders -= 1;
+        let last = state.senders == 0;
+        drop(state);
+        if last {
+            self.shared.readers.wake_all();
+        }
+    }
+}
+
+/// The receiving half of a [`channel`].
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Receives the oldest value, sleeping while the channel is empty.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let value = self.shared.wait(&self.shared.readers, |state| {
+            match state.buffer.pop_front() {
+                Some(value) => Some(Ok(value)),
+                None if state.senders == 0 => Some(Err(RecvError)),
+                None => None,
+            }
+        })?;
+        self.shared.writers.wake_one();
+        Ok(value)
+    }
+}
+
+impl<T: Copy> Receiver<T> {
+    /// Receives up to `buf.len()` values, sleeping until there is at least one, and returns how
+    /// many it received.
+    pub fn recv_slice(&self, buf: &mut [T]) -> Result<usize, RecvError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let n = self.shared.wait(&self.shared.readers, |state| {
+            if state.buffer.is_empty() {
+                return (state.senders == 0).then_some(Err(RecvError));
+            }
+            let n = buf.len().min(state.buffer.len());
+            for (slot, value) in buf.iter_mut().zip(state.buffer.drain(..n)) {
+                *slot = value;
+            }
+            Some(Ok(n))
+        })?;
+        self.shared.writers.wake_all();
+        Ok(n)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock();
+        state.receiver = false;
+        // Drop what was never received outside of the lock.
+        let buffer = mem::take(&mut state.buffer);
+        drop(state);
+        drop(buffer);
+        self.shared.writers.wake_all();
+    }
+}
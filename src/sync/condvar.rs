@@ -0,0 +1,57 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{MutexGuard, WaitQueue};
+
+/// A condition variable, used together with a [`Mutex`](super::Mutex).
+///
+/// As with `std`, waits can wake up spuriously; use [`Condvar::wait_while`] to re-check the
+/// condition.
+pub struct Condvar {
+    /// Bumped by every notification, so that a waiter can tell whether one happened after it
+    /// released the mutex.
+    seq: AtomicU64,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            seq: AtomicU64::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Releases `guard`, blocks until notified, and reacquires the mutex.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex();
+        let seq = self.seq.load(Ordering::Relaxed);
+        drop(guard);
+        self.waiters
+            .wait_until(|| (self.seq.load(Ordering::Relaxed) != seq).then_some(()));
+        mutex.lock()
+    }
+
+    /// Blocks while `condition` returns `true` for the protected data.
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Wakes up one waiting thread.
+    pub fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        self.waiters.wake_one();
+    }
+
+    /// Wakes up all waiting threads.
+    pub fn notify_all(&self) {
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        self.waiters.wake_all();
+    }
+}
@@ -0,0 +1,65 @@
+use core::{
+    marker::PhantomData,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+};
+
+use crate::arch::irq;
+
+/// A spinlock that masks interrupts on the local core while it is held.
+///
+/// This is the lock for data shared with interrupt handlers: the holder cannot be interrupted by
+/// a handler that then spins on the same lock.
+pub struct IrqSpinLock<T: ?Sized> {
+    inner: spin::mutex::SpinMutex<T>,
+}
+
+pub struct IrqSpinLockGuard<'a, T: ?Sized> {
+    guard: ManuallyDrop<spin::mutex::SpinMutexGuard<'a, T>>,
+    daif: u64,
+    /// The saved interrupt mask belongs to this core.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            inner: spin::mutex::SpinMutex::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> IrqSpinLock<T> {
+    /// Masks interrupts and acquires the lock. The previous mask is restored when the guard is
+    /// dropped.
+    pub fn lock(&self) -> IrqSpinLockGuard<'_, T> {
+        let daif = irq::local_irq_save();
+        IrqSpinLockGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            daif,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for IrqSpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        // Release the lock before interrupts can come in again.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        irq::local_irq_restore(self.daif);
+    }
+}
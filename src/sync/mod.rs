@@ -0,0 +1,29 @@
+//! Synchronization primitives.
+//!
+//! [`IrqSpinLock`] is for data shared with interrupt handlers, including the scheduler and the
+//! allocators, and [`SpinMutex`] for short critical sections that are never entered from one.
+//! Everything else should use the blocking primitives, which put waiting threads to sleep instead
+//! of spinning through their time slice. Threads pass data to each other through a [`channel`].
+
+mod channel;
+// Condvar and Semaphore are not used by the kernel yet.
+#[allow(dead_code)]
+mod condvar;
+mod irq_spin_lock;
+mod mutex;
+mod rwlock;
+#[allow(dead_code)]
+mod semaphore;
+mod spin_mutex;
+mod wait_queue;
+
+pub use channel::{channel, Receiver, Sender};
+#[allow(unused_imports)]
+pub use condvar::Condvar;
+pub use irq_spin_lock::IrqSpinLock;
+pub use mutex::{Mutex, MutexGuard};
+pub use rwlock::RwLock;
+#[allow(unused_imports)]
+pub use semaphore::Semaphore;
+pub use spin_mutex::SpinMutex;
+pub use wait_queue::WaitQueue;
@@ -0,0 +1,30 @@
+use crate::arch::irq;
+
+pub type SpinMutexGuard<'a, T> = spin::mutex::SpinMutexGuard<'a, T>;
+
+/// A spinlock for data that is never touched from interrupt handlers.
+///
+/// A handler spinning on a lock held by the thread it interrupted would never get it, so debug
+/// builds panic if this is locked in interrupt context. Use [`IrqSpinLock`](super::IrqSpinLock)
+/// there instead.
+pub struct SpinMutex<T: ?Sized> {
+    inner: spin::mutex::SpinMutex<T>,
+}
+
+impl<T> SpinMutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            inner: spin::mutex::SpinMutex::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> SpinMutex<T> {
+    pub fn lock(&self) -> SpinMutexGuard<'_, T> {
+        debug_assert!(
+            !irq::in_interrupt(),
+            "SpinMutex locked in interrupt context"
+        );
+        self.inner.lock()
+    }
+}
@@ -0,0 +1,62 @@
+use alloc::collections::VecDeque;
+
+use crate::{arch::irq, sync::IrqSpinLock, thread::Thread};
+
+/// A queue of threads blocked until some condition holds.
+///
+/// Code that makes the condition true calls [`WaitQueue::wake_one`] or [`WaitQueue::wake_all`]
+/// afterwards. Must not be waited on from interrupt context.
+pub struct WaitQueue {
+    waiters: IrqSpinLock<VecDeque<*mut Thread>>,
+}
+
+// The thread pointers are only handed back to the scheduler.
+unsafe impl Send for WaitQueue {}
+unsafe impl Sync for WaitQueue {}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: IrqSpinLock::new(VecDeque::new()),
+        }
+    }
+
+    /// Blocks the current thread until `condition` returns `Some`, and returns its value.
+    ///
+    /// `condition` is called with the queue locked and IRQs masked, so a wakeup cannot slip in
+    /// between checking it and going to sleep. It should be short and must not block.
+    pub fn wait_until<R>(&self, mut condition: impl FnMut() -> Option<R>) -> R {
+        irq::without_interrupts(|| loop {
+            {
+                let mut waiters = self.waiters.lock();
+                if let Some(ret) = condition() {
+                    return ret;
+                }
+                waiters.push_back(Thread::current_ptr());
+            }
+            Thread::block_current();
+        })
+    }
+
+    /// Wakes the longest waiting thread. Returns `false` if there was none.
+    pub fn wake_one(&self) -> bool {
+        let waiter = self.waiters.lock().pop_front();
+        match waiter {
+            Some(thread) => {
+                Thread::wake(thread);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes all waiting threads and returns how many there were.
+    pub fn wake_all(&self) -> usize {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        let count = waiters.len();
+        for thread in waiters {
+            Thread::wake(thread);
+        }
+        count
+    }
+}
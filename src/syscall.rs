@@ -0,0 +1,364 @@
+//! System calls.
+//!
+//! User threads enter the kernel with `svc #0`, with the system call number in x8 and up to six
+//! arguments in x0 to x5. The result is returned in x0: a non-negative value on success, or a
+//! negated [`Errno`] on failure.
+
+use crate::{
+    arch::{
+        address_space::{AddressSpace, MapError, USER_END},
+        mmu::{MemoryKind, PAGE_SIZE},
+        user::{self, UserFault},
+    },
+    thread::{self, Thread},
+    time::Duration,
+    utils::align_up,
+    vfs::{self, FdTable, FsError, InodeKind, OpenOptions},
+};
+use alloc::sync::Arc;
+use core::str;
+
+// System call numbers.
+
+/// `write(fd, buf, len)`: writes the `len` bytes at `buf` to `fd`, and returns how many it wrote.
+pub const SYS_WRITE: usize = 0;
+/// `yield()`: gives the rest of the time slice to other threads.
+pub const SYS_YIELD: usize = 1;
+/// `exit(code)`: ends the calling thread with `code`.
+pub const SYS_EXIT: usize = 2;
+/// `sleep(ms)`: blocks for at least `ms` milliseconds.
+pub const SYS_SLEEP: usize = 3;
+/// `spawn(entry, sp, arg)`: starts a thread in the caller's address space, at `entry` with the
+/// stack pointer `sp` and `arg` in x0.
+pub const SYS_SPAWN: usize = 4;
+/// `mmap(addr, len, prot)`: maps `len` bytes of zeroed memory at `addr`, or anywhere if `addr` is
+/// 0, and returns the address. `prot` is a combination of the `PROT_*` flags. `len` is at most
+/// 64 MiB.
+pub const SYS_MMAP: usize = 5;
+/// `munmap(addr, len)`: unmaps `[addr, addr + len)`.
+pub const SYS_MUNMAP: usize = 6;
+/// `read(fd, buf, len)`: reads up to `len` bytes from `fd` to `buf`, and returns how many it
+/// read. 0 means end of file.
+pub const SYS_READ: usize = 7;
+/// `open(path, path_len, flags)`: opens the absolute path of `path_len` bytes at `path`, and
+/// returns the new file descriptor. `flags` is one of the `O_*` access modes, optionally with
+/// `O_CREAT`.
+pub const SYS_OPEN: usize = 8;
+/// `close(fd)`: closes `fd`.
+pub const SYS_CLOSE: usize = 9;
+/// `readdir(fd, index, buf, len)`: copies the NUL terminated name of entry `index` of the
+/// directory `fd` to the `len` bytes at `buf`, and returns its `DT_*` type, or 0 past the last
+/// entry.
+pub const SYS_READDIR: usize = 10;
+/// `pipe(fds)`: creates a pipe, and stores the file descriptors of its read end and its write end
+/// as two 32-bit integers at `fds`.
+pub const SYS_PIPE: usize = 11;
+
+const SYSCALL_COUNT: usize = 12;
+
+/// Error numbers, as in Linux.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(isize)]
+pub enum Errno {
+    /// No such file or directory.
+    NoEnt = 2,
+    /// Interrupted system call.
+    Intr = 4,
+    /// Bad file descriptor.
+    BadFd = 9,
+    /// Out of memory.
+    NoMem = 12,
+    /// Bad address.
+    Fault = 14,
+    /// Already exists.
+    Exists = 17,
+    /// Not a directory.
+    NotDir = 20,
+    /// Is a directory.
+    IsDir = 21,
+    /// Invalid argument.
+    Inval = 22,
+    /// Too many open files.
+    MFile = 24,
+    /// Broken pipe.
+    Pipe = 32,
+    /// File name too long.
+    NameTooLong = 36,
+    /// No such system call.
+    NoSys = 38,
+}
+
+impl From<UserFault> for Errno {
+    fn from(_: UserFault) -> Self {
+        Errno::Fault
+    }
+}
+
+impl From<MapError> for Errno {
+    fn from(error: MapError) -> Self {
+        match error {
+            MapError::OutOfMemory | MapError::OutOfAsids => Errno::NoMem,
+            MapError::InvalidRange => Errno::Inval,
+            MapError::AlreadyMapped => Errno::Exists,
+            MapError::NotMapped => Errno::Fault,
+        }
+    }
+}
+
+impl From<FsError> for Errno {
+    fn from(error: FsError) -> Self {
+        match error {
+            FsError::NotFound => Errno::NoEnt,
+            FsError::NotADirectory => Errno::NotDir,
+            FsError::IsADirectory => Errno::IsDir,
+            FsError::AlreadyExists => Errno::Exists,
+            FsError::InvalidPath | FsError::NotSupported => Errno::Inval,
+            FsError::BadFileDescriptor => Errno::BadFd,
+            FsError::TooManyOpenFiles => Errno::MFile,
+            FsError::BrokenPipe => Errno::Pipe,
+            FsError::Interrupted => Errno::Intr,
+        }
+    }
+}
+
+type SyscallResult = Result<usize, Errno>;
+
+type SyscallHandler = fn(args: &[usize; 6]) -> SyscallResult;
+
+/// Handlers, indexed by system call number.
+static SYSCALL_TABLE: [SyscallHandler; SYSCALL_COUNT] = {
+    let mut table: [SyscallHandler; SYSCALL_COUNT] = [sys_unimplemented; SYSCALL_COUNT];
+    table[SYS_WRITE] = sys_write;
+    table[SYS_YIELD] = sys_yield;
+    table[SYS_EXIT] = sys_exit;
+    table[SYS_SLEEP] = sys_sleep;
+    table[SYS_SPAWN] = sys_spawn;
+    table[SYS_MMAP] = sys_mmap;
+    table[SYS_MUNMAP] = sys_munmap;
+    table[SYS_READ] = sys_read;
+    table[SYS_OPEN] = sys_open;
+    table[SYS_CLOSE] = sys_close;
+    table[SYS_READDIR] = sys_readdir;
+    table[SYS_PIPE] = sys_pipe;
+    table
+};
+
+/// Runs system call `number` and returns the value for x0.
+///
+/// Called from the synchronous exception vector with IRQs enabled.
+pub fn dispatch(number: usize, args: &[usize; 6]) -> isize {
+    let result = match SYSCALL_TABLE.get(number) {
+        Some(handler) => handler(args),
+        None => Err(Errno::NoSys),
+    };
+    match result {
+        Ok(value) => value as isize,
+        Err(errno) => -(errno as isize),
+    }
+}
+
+// Access modes and flags of `open`, as in Linux.
+pub const O_RDONLY: usize = 0;
+pub const O_WRONLY: usize = 1;
+pub const O_RDWR: usize = 2;
+pub const O_ACCMODE: usize = 3;
+pub const O_CREAT: usize = 0o100;
+
+// Entry types of `readdir`, as in Linux.
+pub const DT_CHR: usize = 2;
+pub const DT_DIR: usize = 4;
+pub const DT_REG: usize = 8;
+
+/// Longest path `open` accepts.
+const PATH_MAX: usize = 256;
+
+// Access flags of `mmap`. Mappings are never writable and executable at the same time.
+pub const PROT_READ: usize = 1 << 0;
+pub const PROT_WRITE: usize = 1 << 1;
+pub const PROT_EXEC: usize = 1 << 2;
+
+/// Largest mapping a single `mmap` creates.
+const MMAP_MAX: usize = 64 << 20;
+
+/// The address space of the calling thread.
+fn current_address_space() -> Result<Arc<AddressSpace>, Errno> {
+    Thread::current()
+        .address_space()
+        .cloned()
+        .ok_or(Errno::Inval)
+}
+
+/// The open files of the calling thread.
+fn current_files() -> Result<Arc<FdTable>, Errno> {
+    Thread::current().files().cloned().ok_or(Errno::Inval)
+}
+
+fn sys_unimplemented(_args: &[usize; 6]) -> SyscallResult {
+    Err(Errno::NoSys)
+}
+
+fn sys_write(args: &[usize; 6]) -> SyscallResult {
+    let [fd, buf, len, ..] = *args;
+    let file = current_files()?.get(fd)?;
+    user::check_user_range(buf, len)?;
+
+    let mut chunk = [0; 256];
+    let mut written = 0;
+    while written < len {
+        let n = chunk.len().min(len - written);
+        user::copy_from_user(&mut chunk[..n], buf + written)?;
+        let n = file.write(&chunk[..n])?;
+        if n == 0 {
+            break;
+        }
+        written += n;
+    }
+    Ok(written)
+}
+
+fn sys_read(args: &[usize; 6]) -> SyscallResult {
+    let [fd, buf, len, ..] = *args;
+    let file = current_files()?.get(fd)?;
+    user::check_user_range(buf, len)?;
+
+    // A single read, which may return less than asked for: reading more from a pipe or the
+    // console could block although there was data.
+    let mut chunk = [0; 1024];
+    let n = chunk.len().min(len);
+    let n = file.read(&mut chunk[..n])?;
+    user::copy_to_user(buf, &chunk[..n])?;
+    Ok(n)
+}
+
+fn sys_open(args: &[usize; 6]) -> SyscallResult {
+    let [path, path_len, flags, ..] = *args;
+    if path_len > PATH_MAX {
+        return Err(Errno::NameTooLong);
+    }
+    let mut buf = [0; PATH_MAX];
+    user::copy_from_user(&mut buf[..path_len], path)?;
+    let path = str::from_utf8(&buf[..path_len]).map_err(|_| Errno::Inval)?;
+
+    if flags & !(O_ACCMODE | O_CREAT) != 0 {
+        return Err(Errno::Inval);
+    }
+    let (read, write) = match flags & O_ACCMODE {
+        O_RDONLY => (true, false),
+        O_WRONLY => (false, true),
+        O_RDWR => (true, true),
+        _ => return Err(Errno::Inval),
+    };
+    let options = OpenOptions {
+        read,
+        write,
+        create: flags & O_CREAT != 0,
+    };
+
+    let file = vfs::open(path, options)?;
+    Ok(current_files()?.insert(file)?)
+}
+
+fn sys_close(args: &[usize; 6]) -> SyscallResult {
+    current_files()?.remove(args[0])?;
+    Ok(0)
+}
+
+fn sys_readdir(args: &[usize; 6]) -> SyscallResult {
+    let [fd, index, buf, len, ..] = *args;
+    let entries = current_files()?.get(fd)?.read_dir()?;
+    let entry = match entries.get(index) {
+        Some(entry) => entry,
+        None => return Ok(0),
+    };
+    let name = entry.name.as_bytes();
+    if name.len() >= len {
+        return Err(Errno::Inval);
+    }
+    user::copy_to_user(buf, name)?;
+    user::copy_to_user(buf + name.len(), &[0])?;
+    Ok(match entry.kind {
+        InodeKind::File => DT_REG,
+        InodeKind::Directory => DT_DIR,
+        InodeKind::CharDevice => DT_CHR,
+    })
+}
+
+fn sys_pipe(args: &[usize; 6]) -> SyscallResult {
+    let fds = args[0];
+    user::check_user_range(fds, 8)?;
+    let files = current_files()?;
+    let (reader, writer) = vfs::pipe();
+    let read_fd = files.insert(reader)?;
+    let write_fd = match files.insert(writer) {
+        Ok(fd) => fd,
+        Err(error) => {
+            files.remove(read_fd)?;
+            return Err(error.into());
+        }
+    };
+
+    let mut buf = [0; 8];
+    buf[..4].copy_from_slice(&(read_fd as u32).to_le_bytes());
+    buf[4..].copy_from_slice(&(write_fd as u32).to_le_bytes());
+    if let Err(fault) = user::copy_to_user(fds, &buf) {
+        files.remove(read_fd)?;
+        files.remove(write_fd)?;
+        return Err(fault.into());
+    }
+    Ok(0)
+}
+
+fn sys_yield(_args: &[usize; 6]) -> SyscallResult {
+    Thread::yield_current();
+    Ok(0)
+}
+
+fn sys_exit(args: &[usize; 6]) -> SyscallResult {
+    user::exit_user(args[0] as i32)
+}
+
+fn sys_sleep(args: &[usize; 6]) -> SyscallResult {
+    Thread::sleep(Duration::from_millis(args[0] as u64));
+    Ok(0)
+}
+
+fn sys_spawn(args: &[usize; 6]) -> SyscallResult {
+    let [entry, sp, arg, ..] = *args;
+    if entry >= USER_END || sp > USER_END {
+        return Err(Errno::Inval);
+    }
+    // Detached: the new thread's exit code is dropped.
+    thread::spawn_user(current_address_space()?, current_files()?, entry, sp, arg);
+    Ok(0)
+}
+
+fn sys_mmap(args: &[usize; 6]) -> SyscallResult {
+    let [addr, len, prot, ..] = *args;
+    let kind = match prot {
+        _ if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 => return Err(Errno::Inval),
+        _ if prot & PROT_WRITE != 0 && prot & PROT_EXEC != 0 => return Err(Errno::Inval),
+        _ if prot & PROT_EXEC != 0 => MemoryKind::UserCode,
+        _ if prot & PROT_WRITE != 0 => MemoryKind::UserData,
+        _ => MemoryKind::UserRodata,
+    };
+    if len == 0 || len > MMAP_MAX {
+        return Err(Errno::Inval);
+    }
+
+    let address_space = current_address_space()?;
+    if addr == 0 {
+        Ok(address_space.mmap(len, kind)?)
+    } else {
+        address_space.map_zeroed(addr, align_up(len, PAGE_SIZE), kind)?;
+        Ok(addr)
+    }
+}
+
+fn sys_munmap(args: &[usize; 6]) -> SyscallResult {
+    let [addr, len, ..] = *args;
+    if len > USER_END {
+        return Err(Errno::Inval);
+    }
+    current_address_space()?.unmap(addr, align_up(len, PAGE_SIZE))?;
+    Ok(0)
+}
@@ -1,88 +1,470 @@
 use alloc::collections::VecDeque;
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use crate::{arch, singleton::Singleton, sync::SpinMutex};
+use crate::{
+    allocator::{self, FrameRange},
+    arch,
+    arch::{address_space::AddressSpace, irq},
+    cpu::{self, MAX_CPUS},
+    ipi::{self, Ipi},
+    singleton::Singleton,
+    sync::{IrqSpinLock, WaitQueue},
+    time::{self, Duration},
+    vfs::FdTable,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThreadState {
+    /// On a run queue.
+    Ready,
+    /// Executing on a CPU.
+    Running,
+    /// Waiting for someone to call [`Thread::wake`].
+    Blocked,
+    /// Exited. The stack is freed by [`reap_dead_threads`].
+    Dead,
+}
 
 #[repr(C)]
 pub struct Thread {
-    stack: Box<[u8]>,
+    /// `None` for the placeholder of the boot stack.
+    stack: Option<FrameRange>,
     pub context: arch::thread::ThreadContext,
+    state: IrqSpinLock<ThreadState>,
+    /// Set by `wake` while the thread is still running, e.g. between putting itself on a wait
+    /// queue and blocking. The next `block_current` returns immediately instead.
+    wake_pending: AtomicBool,
+    /// Set while a CPU runs on the thread's stack, which lasts until the switch away from it has
+    /// saved its context.
+    on_cpu: AtomicBool,
+    /// The address space of user threads, loaded while the thread runs.
+    address_space: Option<Arc<AddressSpace>>,
+    /// The open files of user threads.
+    files: Option<Arc<FdTable>>,
+    /// Where a user thread returns to once it exits user space. Saved on entry to user space.
+    pub user_return: arch::thread::ThreadContext,
 }
 
 const STACK_SIZE: usize = 1024 * 1024;
 
+/// Timer ticks a thread may run before it is preempted.
+const TIME_SLICE_TICKS: u32 = 2;
+
+type ThreadMain = Box<dyn FnOnce() + Send + 'static>;
+
+extern "C" fn thread_start(main: usize) -> ! {
+    finish_switch();
+    // Threads are first switched to with IRQs masked.
+    irq::local_irq_enable();
+    let main = unsafe { Box::from_raw(main as *mut ThreadMain) };
+    main();
+    Thread::exit();
+}
+
+/// Called from the timer interrupt on every tick.
+pub fn tick() {
+    let cpu = cpu::current();
+    if cpu.ticks_left.fetch_sub(1, Ordering::Relaxed) <= 1 {
+        cpu.need_resched.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Switches away from the current thread if its time slice is used up. Called on IRQ exit.
+pub fn preempt_if_needed() {
+    if cpu::current().need_resched.swap(false, Ordering::Relaxed) {
+        Thread::yield_current();
+    }
+}
+
+fn reset_time_slice() {
+    let cpu = cpu::current();
+    cpu.ticks_left.store(TIME_SLICE_TICKS, Ordering::Relaxed);
+    cpu.need_resched.store(false, Ordering::Relaxed);
+}
+
+/// Completes a switch on the new thread: the previous one can now run elsewhere.
+fn finish_switch() {
+    let prev = cpu::current()
+        .prev_thread
+        .swap(core::ptr::null_mut(), Ordering::Relaxed);
+    if let Some(prev) = unsafe { prev.as_ref() } {
+        prev.on_cpu.store(false, Ordering::Release);
+    }
+}
+
+/// Frees the threads that have exited since the last call.
+///
+/// Must be called from thread context: dropping a user thread's address space frees its ASID
+/// under a [`SpinMutex`](crate::sync::SpinMutex). Freeing memory alone would be fine, as the
+/// allocators are safe to use from interrupt handlers.
+pub fn reap_dead_threads() {
+    let dead = core::mem::take(&mut *SCHEDULER.get().dead.lock());
+    for thread in dead {
+        if unsafe { (*thread).on_cpu.load(Ordering::Acquire) } {
+            // Still switching away from its final moments; try again next time.
+            SCHEDULER.get().dead.lock().push(thread);
+        } else {
+            drop(unsafe { Box::from_raw(thread) });
+        }
+    }
+}
+
+/// Prints what each CPU is running and what is queued, for debugging.
+#[cfg(feature = "debug_shell")]
+pub fn dump_threads() {
+    let describe = |thread: *mut Thread| {
+        let thread = unsafe { &*thread };
+        let kind = if thread.address_space.is_some() {
+            "user"
+        } else {
+            "kernel"
+        };
+        (thread as *const Thread, *thread.state.lock(), kind)
+    };
+
+    let scheduler = SCHEDULER.get();
+    for cpu in cpu::online_cpus() {
+        let current = cpu.current_thread.load(Ordering::Relaxed);
+        if current == cpu.idle_thread.load(Ordering::Relaxed) {
+            crate::println!("CPU {}: idle", cpu.id());
+        } else {
+            crate::println!("CPU {}: running {:p}", cpu.id(), current);
+        }
+        // Queued threads cannot exit, so they can be looked at under the lock.
+        let queue: Vec<_> = scheduler.run_queues[cpu.id()]
+            .lock()
+            .iter()
+            .map(|&thread| describe(thread))
+            .collect();
+        for (thread, state, kind) in queue {
+            crate::println!("    {:p} {:?} {}", thread, state, kind);
+        }
+    }
+    crate::println!(
+        "{} exited threads not freed yet",
+        scheduler.dead.lock().len()
+    );
+}
+
+/// Starts a new thread running `f` and returns a handle to collect its result.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_in(None, None, f)
+}
+
+/// Starts a new thread running at EL0 in `address_space` with the open files `files`, from
+/// `entry` with the stack pointer `sp` and `arg` as its first argument. The handle collects its
+/// exit code.
+pub fn spawn_user(
+    address_space: Arc<AddressSpace>,
+    files: Arc<FdTable>,
+    entry: usize,
+    sp: usize,
+    arg: usize,
+) -> JoinHandle<i32> {
+    spawn_in(Some(address_space), Some(files), move || {
+        arch::user::enter_user(entry, sp, arg)
+    })
+}
+
+fn spawn_in<F, T>(
+    address_space: Option<Arc<AddressSpace>>,
+    files: Option<Arc<FdTable>>,
+    f: F,
+) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    reap_dead_threads();
+
+    let packet = Arc::new(Packet::new());
+    let their_packet = packet.clone();
+    let mut thread = Thread::new(move || their_packet.set(f()));
+    thread.address_space = address_space;
+    thread.files = files;
+    SCHEDULER.get().add(Box::into_raw(Box::new(thread)));
+
+    JoinHandle { packet }
+}
+
+/// Result slot shared between a thread and its [`JoinHandle`].
+struct Packet<T> {
+    result: IrqSpinLock<Option<T>>,
+    joiner: WaitQueue,
+}
+
+impl<T> Packet<T> {
+    fn new() -> Self {
+        Self {
+            result: IrqSpinLock::new(None),
+            joiner: WaitQueue::new(),
+        }
+    }
+
+    fn set(&self, result: T) {
+        *self.result.lock() = Some(result);
+        self.joiner.wake_all();
+    }
+}
+
+/// An owned permission to wait for a thread to finish and take its return value.
+///
+/// Dropping the handle detaches the thread.
+pub struct JoinHandle<T> {
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Blocks until the thread returns, and returns its result.
+    pub fn join(self) -> T {
+        self.packet
+            .joiner
+            .wait_until(|| self.packet.result.lock().take())
+    }
+}
+
 impl Thread {
-    pub fn new(func: fn()) -> Self {
-        let mut stack = Vec::new();
-        stack
-            .try_reserve_exact(STACK_SIZE)
-            .expect("could not allocate stack");
-        stack.resize(STACK_SIZE, 0);
-        let stack = stack.into_boxed_slice();
-        let stack_end = stack.as_ptr_range().end;
+    pub fn new<F>(f: F) -> Self
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let stack =
+            FrameRange::alloc(allocator::order_for(STACK_SIZE)).expect("could not allocate stack");
+        let stack_end = stack.end() as *const u8;
+
+        // Box twice to pass the closure as a thin pointer.
+        let main: Box<ThreadMain> = Box::new(Box::new(f));
+        let context =
+            arch::thread::ThreadContext::new(thread_start, Box::into_raw(main) as usize, stack_end);
 
-        let context = arch::thread::ThreadContext::new(func, stack_end);
+        Self {
+            stack: Some(stack),
+            context,
+            state: IrqSpinLock::new(ThreadState::Ready),
+            wake_pending: AtomicBool::new(false),
+            on_cpu: AtomicBool::new(false),
+            address_space: None,
+            files: None,
+            user_return: arch::thread::ThreadContext::default(),
+        }
+    }
 
-        Self { stack, context }
+    /// The address space of a user thread, `None` for kernel threads.
+    pub fn address_space(&self) -> Option<&Arc<AddressSpace>> {
+        self.address_space.as_ref()
+    }
+
+    /// The open files of a user thread, `None` for kernel threads.
+    pub fn files(&self) -> Option<&Arc<FdTable>> {
+        self.files.as_ref()
     }
 
     pub fn current() -> &'static Thread {
-        unsafe { &*(arch::thread::current_thread() as *const Thread) }
+        unsafe { &*Thread::current_ptr() }
+    }
+
+    /// The kernel stack of the current thread, or `None` while on a boot stack.
+    #[cfg(feature = "alloc_tracking")]
+    pub fn current_stack() -> Option<core::ops::Range<usize>> {
+        let thread = unsafe { Thread::current_ptr().as_ref()? };
+        thread
+            .stack
+            .as_ref()
+            .map(|stack| stack.start()..stack.end())
+    }
+
+    pub fn current_ptr() -> *mut Thread {
+        // Without IRQs masked, the thread could migrate between finding its CPU and reading what
+        // that CPU runs.
+        irq::without_interrupts(|| cpu::current().current_thread.load(Ordering::Relaxed))
     }
 
     pub fn yield_current() {
-        // TODO: handle idle thread
-        let current_thread = Thread::current() as *const _ as *mut _;
-        let next_thread;
+        let daif = irq::local_irq_save();
+        let current_thread = Thread::current_ptr();
+        let idle_thread = cpu::current().idle_thread.load(Ordering::Relaxed);
+        reset_time_slice();
+
+        // If there is nothing else to run, keep going with a fresh time slice.
+        if let Some(next_thread) = SCHEDULER.get().pick_next() {
+            if current_thread != idle_thread {
+                unsafe { *(*current_thread).state.lock() = ThreadState::Ready };
+                SCHEDULER.get().add(current_thread);
+            }
+            Thread::switch(current_thread, next_thread);
+        }
+        irq::local_irq_restore(daif);
+    }
+
+    /// Switches away from the current thread, leaving it in `state`.
+    ///
+    /// Must be called with IRQs masked.
+    fn switch_away(state: ThreadState) {
+        let current_thread = Thread::current_ptr();
         {
-            // TODO: multiple CPU case?
-            let mut scheduler = SCHEDULER.get().lock();
-            next_thread = scheduler.schedule();
-            scheduler.queue.push_back(current_thread);
+            let current = unsafe { &*current_thread };
+            let mut current_state = current.state.lock();
+            if state == ThreadState::Blocked && current.wake_pending.swap(false, Ordering::Relaxed)
+            {
+                return;
+            }
+            *current_state = state;
         }
+        if state == ThreadState::Dead {
+            SCHEDULER.get().dead.lock().push(current_thread);
+        }
+        reset_time_slice();
 
+        let next_thread = SCHEDULER
+            .get()
+            .pick_next()
+            .unwrap_or_else(|| cpu::current().idle_thread.load(Ordering::Relaxed));
         Thread::switch(current_thread, next_thread);
     }
 
+    /// Switches away from the current thread without putting it back on a run queue.
+    ///
+    /// Must be called with IRQs masked, after the thread has been handed to whoever is going to
+    /// `wake` it. Returns right away if it has already been woken.
+    pub fn block_current() {
+        Thread::switch_away(ThreadState::Blocked);
+    }
+
+    /// Terminates the current thread. Its stack is freed later by [`reap_dead_threads`].
+    pub fn exit() -> ! {
+        irq::local_irq_disable();
+        Thread::switch_away(ThreadState::Dead);
+        unreachable!("dead thread was scheduled again");
+    }
+
+    /// Makes `thread` runnable again after `block_current`.
+    ///
+    /// If the thread has not blocked yet, its next `block_current` returns immediately.
+    pub fn wake(thread: *mut Thread) {
+        let t = unsafe { &*thread };
+        let mut state = t.state.lock();
+        match *state {
+            ThreadState::Blocked => {
+                *state = ThreadState::Ready;
+                drop(state);
+                // The thread may have blocked on another CPU that is still switching away from it.
+                while t.on_cpu.load(Ordering::Acquire) {
+                    core::hint::spin_loop();
+                }
+                SCHEDULER.get().add(thread);
+            }
+            ThreadState::Running => t.wake_pending.store(true, Ordering::Relaxed),
+            ThreadState::Ready | ThreadState::Dead => {}
+        }
+    }
+
+    /// Blocks the current thread for at least `duration`.
+    pub fn sleep(duration: Duration) {
+        fn wake_sleeper(thread: usize) {
+            Thread::wake(thread as *mut Thread);
+        }
+
+        let deadline = time::now() + duration;
+        irq::without_interrupts(|| {
+            let current_thread = Thread::current_ptr() as usize;
+            time::add_timer(deadline, wake_sleeper, current_thread);
+            Thread::block_current();
+        });
+    }
+
+    /// Switches the executing CPU from `from` to `to`. Must be called with IRQs masked.
     pub fn switch(from: *mut Thread, to: *mut Thread) {
+        if from == to {
+            // Blocked and woken up again before there was anything else to run.
+            unsafe { *(*to).state.lock() = ThreadState::Running };
+            return;
+        }
+
+        let cpu = cpu::current();
+        unsafe {
+            debug_assert!(!(*to).on_cpu.load(Ordering::Relaxed));
+            (*to).on_cpu.store(true, Ordering::Relaxed);
+            *(*to).state.lock() = ThreadState::Running;
+        }
+        cpu.prev_thread.store(from, Ordering::Relaxed);
+        cpu.current_thread.store(to, Ordering::Relaxed);
         arch::thread::thread_switch(from, to);
+        finish_switch();
     }
 
+    /// Starts running threads on the executing CPU, beginning with `init`.
     pub fn start_with(init: *mut Thread) -> ! {
         let mut placeholder = Thread {
-            stack: Box::new([]),
+            stack: None,
             context: arch::thread::ThreadContext::default(),
+            state: IrqSpinLock::new(ThreadState::Running),
+            wake_pending: AtomicBool::new(false),
+            on_cpu: AtomicBool::new(true),
+            address_space: None,
+            files: None,
+            user_return: arch::thread::ThreadContext::default(),
         };
 
+        irq::local_irq_disable();
+        reset_time_slice();
         Thread::switch(&mut placeholder as *mut Thread, init);
         unreachable!();
     }
 }
 
-pub static SCHEDULER: Singleton<SpinMutex<Scheduler>> = Singleton::new();
+pub static SCHEDULER: Singleton<Scheduler> = Singleton::new();
 
+/// Per-CPU run queues. A CPU whose own queue is empty steals from the others.
 pub struct Scheduler {
-    queue: VecDeque<*mut Thread>,
-    idle_thread: *mut Thread,
+    run_queues: [IrqSpinLock<VecDeque<*mut Thread>>; MAX_CPUS],
+    /// Threads that have exited, waiting for [`reap_dead_threads`].
+    dead: IrqSpinLock<Vec<*mut Thread>>,
 }
 
 impl Scheduler {
-    pub fn new(idle_thread: *mut Thread) -> Self {
+    pub fn new() -> Self {
         Self {
-            queue: VecDeque::new(),
-            idle_thread,
+            run_queues: core::array::from_fn(|_| IrqSpinLock::new(VecDeque::new())),
+            dead: IrqSpinLock::new(Vec::new()),
+        }
+    }
+
+    /// Queues `thread` on the executing CPU, and wakes up an idle CPU to steal it.
+    pub fn add(&self, thread: *mut Thread) {
+        let this_cpu = cpu::current().id();
+        self.run_queues[this_cpu].lock().push_back(thread);
+
+        if let Some(idle_cpu) = cpu::online_cpus().find(|cpu| cpu.id() != this_cpu && cpu.is_idle())
+        {
+            ipi::send(idle_cpu, Ipi::Reschedule);
         }
     }
 
-    pub fn add(&mut self, thread: *mut Thread) {
-        self.queue.push_back(thread);
+    /// Takes the next thread to run on the executing CPU, if there is one.
+    fn pick_next(&self) -> Option<*mut Thread> {
+        let this_cpu = cpu::current().id();
+        if let Some(thread) = self.run_queues[this_cpu].lock().pop_front() {
+            return Some(thread);
+        }
+        (1..MAX_CPUS).find_map(|i| self.steal((this_cpu + i) % MAX_CPUS))
     }
 
-    pub fn schedule(&mut self) -> *mut Thread {
-        let next_thread = self.queue.pop_front().unwrap_or(self.idle_thread);
-        next_thread
+    /// Takes the thread that has waited the least on `victim`'s run queue.
+    fn steal(&self, victim: usize) -> Option<*mut Thread> {
+        let mut queue = self.run_queues[victim].lock();
+        // A thread that yielded is queued before its CPU has switched away from it.
+        let pos = queue
+            .iter()
+            .rposition(|&thread| unsafe { !(*thread).on_cpu.load(Ordering::Acquire) })?;
+        queue.remove(pos)
     }
 }
 
-// TODO: remove this when scheduler is per CPU
+// The thread pointers are owned by the scheduler.
 unsafe impl Send for Scheduler {}
+unsafe impl Sync for Scheduler {}
@@ -0,0 +1,109 @@
+//! Monotonic clock and timer queue.
+//!
+//! Time is read from the system counter. Timers are checked on every scheduler tick, so they fire
+//! with the tick's granularity (see `arch::timer::TICK_HZ`).
+
+use alloc::vec::Vec;
+use core::ops::{Add, Sub};
+
+pub use core::time::Duration;
+
+use crate::{arch, sync::IrqSpinLock};
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// A point in time, as a value of the monotonic system counter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant(arch::timer::counter())
+    }
+
+    /// Returns the time elapsed since `earlier`, or zero if `earlier` is later than `self`.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.0.saturating_sub(earlier.0))
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0.saturating_add(duration_to_ticks(rhs)))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+fn ticks_to_duration(ticks: u64) -> Duration {
+    let nanos = ticks as u128 * NANOS_PER_SEC / arch::timer::frequency() as u128;
+    Duration::new(
+        (nanos / NANOS_PER_SEC) as u64,
+        (nanos % NANOS_PER_SEC) as u32,
+    )
+}
+
+fn duration_to_ticks(duration: Duration) -> u64 {
+    let ticks = duration.as_nanos() * arch::timer::frequency() as u128 / NANOS_PER_SEC;
+    ticks.try_into().unwrap_or(u64::MAX)
+}
+
+/// Returns the current value of the monotonic clock.
+pub fn now() -> Instant {
+    Instant::now()
+}
+
+/// Busy-waits for `duration`. Usable before interrupts and the scheduler are up.
+#[cfg_attr(not(feature = "bsp_pinephone"), allow(dead_code))]
+pub fn delay(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+struct Timer {
+    deadline: Instant,
+    callback: fn(usize),
+    data: usize,
+}
+
+/// Pending timers, sorted by descending deadline so that the next one to expire is last.
+static TIMERS: IrqSpinLock<Vec<Timer>> = IrqSpinLock::new(Vec::new());
+
+/// Arranges for `callback(data)` to be called from the timer interrupt once `deadline` passes.
+pub fn add_timer(deadline: Instant, callback: fn(usize), data: usize) {
+    let mut timers = TIMERS.lock();
+    let pos = timers.partition_point(|timer| timer.deadline > deadline);
+    timers.insert(
+        pos,
+        Timer {
+            deadline,
+            callback,
+            data,
+        },
+    );
+}
+
+/// Runs the callbacks of all expired timers. Called from the timer interrupt.
+pub fn run_expired_timers() {
+    let now = Instant::now();
+    loop {
+        let timer = {
+            let mut timers = TIMERS.lock();
+            match timers.last() {
+                Some(timer) if timer.deadline <= now => timers.pop().unwrap(),
+                _ => break,
+            }
+        };
+        (timer.callback)(timer.data);
+    }
+}
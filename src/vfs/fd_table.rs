@@ -0,0 +1,73 @@
+use alloc::{sync::Arc, vec::Vec};
+
+use super::{File, FsError};
+use crate::sync::Mutex;
+
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
+/// Most descriptors a table can hold.
+const MAX_FDS: usize = 64;
+
+/// The open files of a process, indexed by file descriptor.
+///
+/// Shared by all threads of a user program.
+pub struct FdTable {
+    files: Mutex<Vec<Option<Arc<dyn File>>>>,
+}
+
+impl FdTable {
+    pub fn new() -> Self {
+        Self {
+            files: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// A table with `file` as standard input, output and error.
+    pub fn with_stdio(file: Arc<dyn File>) -> Self {
+        let table = Self::new();
+        for fd in [STDIN, STDOUT, STDERR] {
+            let new_fd = table.insert(file.clone()).unwrap();
+            debug_assert_eq!(new_fd, fd);
+        }
+        table
+    }
+
+    /// Adds `file` under the lowest free descriptor, and returns the descriptor.
+    pub fn insert(&self, file: Arc<dyn File>) -> Result<usize, FsError> {
+        let mut files = self.files.lock();
+        if let Some(fd) = files.iter().position(Option::is_none) {
+            files[fd] = Some(file);
+            return Ok(fd);
+        }
+        if files.len() == MAX_FDS {
+            return Err(FsError::TooManyOpenFiles);
+        }
+        files.push(Some(file));
+        Ok(files.len() - 1)
+    }
+
+    /// Returns the file open as `fd`.
+    pub fn get(&self, fd: usize) -> Result<Arc<dyn File>, FsError> {
+        self.files
+            .lock()
+            .get(fd)
+            .cloned()
+            .flatten()
+            .ok_or(FsError::BadFileDescriptor)
+    }
+
+    /// Closes `fd`. The file is closed once no other descriptor refers to it.
+    pub fn remove(&self, fd: usize) -> Result<(), FsError> {
+        let file = self
+            .files
+            .lock()
+            .get_mut(fd)
+            .and_then(Option::take)
+            .ok_or(FsError::BadFileDescriptor)?;
+        // Outside of the lock, as closing a file may block.
+        drop(file);
+        Ok(())
+    }
+}
@@ -0,0 +1,289 @@
+//! The virtual filesystem.
+//!
+//! Filesystems provide [`Inode`]s and are mounted at absolute paths. Opening a path gives a
+//! [`File`], which user threads reach through the descriptors in their [`FdTable`].
+//!
+//! Paths are resolved lexically: `..` removes the previous component before any lookup, so it
+//! crosses mount points the way one would expect.
+
+mod fd_table;
+mod pipe;
+
+pub use fd_table::FdTable;
+pub use pipe::pipe;
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::fmt;
+
+use crate::sync::{Mutex, RwLock};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsError {
+    /// No such file or directory.
+    NotFound,
+    /// A path component is not a directory.
+    NotADirectory,
+    /// Expected a file, found a directory.
+    IsADirectory,
+    /// The entry already exists.
+    AlreadyExists,
+    /// The path is relative, or a name is empty or reserved.
+    InvalidPath,
+    /// The inode does not support the operation.
+    NotSupported,
+    /// No such file descriptor, or it is not open for the operation.
+    BadFileDescriptor,
+    /// The file descriptor table is full.
+    TooManyOpenFiles,
+    /// Writing to a pipe whose read end is closed.
+    BrokenPipe,
+    /// A blocking read was interrupted, e.g. by Ctrl-C on the console.
+    Interrupted,
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsError::NotFound => write!(f, "no such file or directory"),
+            FsError::NotADirectory => write!(f, "not a directory"),
+            FsError::IsADirectory => write!(f, "is a directory"),
+            FsError::AlreadyExists => write!(f, "already exists"),
+            FsError::InvalidPath => write!(f, "invalid path"),
+            FsError::NotSupported => write!(f, "operation not supported"),
+            FsError::BadFileDescriptor => write!(f, "bad file descriptor"),
+            FsError::TooManyOpenFiles => write!(f, "too many open files"),
+            FsError::BrokenPipe => write!(f, "broken pipe"),
+            FsError::Interrupted => write!(f, "interrupted"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InodeKind {
+    File,
+    Directory,
+    /// A device that reads and writes a stream of bytes, ignoring offsets.
+    CharDevice,
+}
+
+/// A file, directory or device of a filesystem.
+///
+/// The default implementations fail as appropriate for the other kinds, so a file only implements
+/// the file methods and a directory only the directory methods.
+pub trait Inode: Send + Sync {
+    fn kind(&self) -> InodeKind;
+
+    /// Size of a file in bytes.
+    fn len(&self) -> usize {
+        0
+    }
+
+    /// Reads at `offset` into `buf`, and returns the number of bytes read, 0 at the end.
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::IsADirectory)
+    }
+
+    /// Writes `data` at `offset`, and returns the number of bytes written.
+    fn write_at(&self, _offset: usize, _data: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::IsADirectory)
+    }
+
+    /// Returns the entry `name` of a directory.
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Creates an empty entry `name` of `kind` in a directory.
+    fn create(&self, _name: &str, _kind: InodeKind) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Lists the entries of a directory.
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+}
+
+/// An entry of a directory.
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: InodeKind,
+}
+
+/// An open file.
+pub trait File: Send + Sync {
+    /// Reads into `buf` and returns the number of bytes read, 0 at the end.
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError>;
+
+    /// Writes `data` and returns the number of bytes written.
+    fn write(&self, data: &[u8]) -> Result<usize, FsError>;
+
+    /// Lists the entries of an open directory.
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+}
+
+/// What an opened file may be used for.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OpenOptions {
+    pub read: bool,
+    pub write: bool,
+    /// Create a file if the path does not exist.
+    pub create: bool,
+}
+
+/// An [`Inode`] opened with [`open`]. Files are read and written at a position that advances;
+/// devices ignore it.
+struct InodeFile {
+    inode: Arc<dyn Inode>,
+    options: OpenOptions,
+    position: Mutex<usize>,
+}
+
+impl File for InodeFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        if !self.options.read {
+            return Err(FsError::BadFileDescriptor);
+        }
+        if self.inode.kind() != InodeKind::File {
+            return self.inode.read_at(0, buf);
+        }
+        let mut position = self.position.lock();
+        let n = self.inode.read_at(*position, buf)?;
+        *position += n;
+        Ok(n)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, FsError> {
+        if !self.options.write {
+            return Err(FsError::BadFileDescriptor);
+        }
+        if self.inode.kind() != InodeKind::File {
+            return self.inode.write_at(0, data);
+        }
+        let mut position = self.position.lock();
+        let n = self.inode.write_at(*position, data)?;
+        *position += n;
+        Ok(n)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        if !self.options.read {
+            return Err(FsError::BadFileDescriptor);
+        }
+        self.inode.read_dir()
+    }
+}
+
+/// A filesystem mounted at `path`, given as its components.
+struct Mount {
+    path: Vec<String>,
+    root: Arc<dyn Inode>,
+}
+
+static MOUNTS: RwLock<Vec<Mount>> = RwLock::new(Vec::new());
+
+/// Splits the absolute `path` into its components, with `.` and `..` resolved.
+fn components(path: &str) -> Result<Vec<&str>, FsError> {
+    let path = path.strip_prefix('/').ok_or(FsError::InvalidPath)?;
+    let mut components = Vec::new();
+    for name in path.split('/') {
+        match name {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            _ => components.push(name),
+        }
+    }
+    Ok(components)
+}
+
+/// Looks up `components`, starting at the innermost mount they are in.
+fn resolve(components: &[&str]) -> Result<Arc<dyn Inode>, FsError> {
+    let mounts = MOUNTS.read();
+    let mount = mounts
+        .iter()
+        .filter(|mount| {
+            mount.path.len() <= components.len()
+                && mount.path.iter().zip(components).all(|(a, b)| a == b)
+        })
+        .max_by_key(|mount| mount.path.len())
+        .ok_or(FsError::NotFound)?;
+
+    components[mount.path.len()..]
+        .iter()
+        .try_fold(mount.root.clone(), |directory, name| directory.lookup(name))
+}
+
+/// Mounts the filesystem with the root directory `root` at `path`.
+///
+/// Except for the first mount at `/`, `path` must be an existing directory. Whatever it contains
+/// stays hidden, as there is no unmounting.
+pub fn mount(path: &str, root: Arc<dyn Inode>) -> Result<(), FsError> {
+    let components = components(path)?;
+    if root.kind() != InodeKind::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    let is_root = components.is_empty();
+    if !is_root && resolve(&components)?.kind() != InodeKind::Directory {
+        return Err(FsError::NotADirectory);
+    }
+
+    let mut mounts = MOUNTS.write();
+    if mounts.iter().any(|mount| mount.path == components) {
+        return Err(FsError::AlreadyExists);
+    }
+    mounts.push(Mount {
+        path: components.iter().map(|name| name.to_string()).collect(),
+        root,
+    });
+    Ok(())
+}
+
+/// Resolves the absolute `path`.
+pub fn lookup(path: &str) -> Result<Arc<dyn Inode>, FsError> {
+    resolve(&components(path)?)
+}
+
+/// Opens the absolute `path`.
+pub fn open(path: &str, options: OpenOptions) -> Result<Arc<dyn File>, FsError> {
+    let components = components(path)?;
+    let inode = match resolve(&components) {
+        Err(FsError::NotFound) if options.create => {
+            let (name, parent) = components.split_last().ok_or(FsError::InvalidPath)?;
+            resolve(parent)?.create(name, InodeKind::File)?
+        }
+        result => result?,
+    };
+    if inode.kind() == InodeKind::Directory && options.write {
+        return Err(FsError::IsADirectory);
+    }
+
+    Ok(Arc::new(InodeFile {
+        inode,
+        options,
+        position: Mutex::new(0),
+    }))
+}
+
+/// Reads the whole file at `path`.
+pub fn read_file(path: &str) -> Result<Vec<u8>, FsError> {
+    let file = lookup(path)?;
+    match file.kind() {
+        InodeKind::File => {}
+        InodeKind::Directory => return Err(FsError::IsADirectory),
+        InodeKind::CharDevice => return Err(FsError::NotSupported),
+    }
+    let mut data = vec![0; file.len()];
+    let len = file.read_at(0, &mut data)?;
+    data.truncate(len);
+    Ok(data)
+}
@@ -0,0 +1,38 @@
+use alloc::sync::Arc;
+
+use super::{File, FsError};
+use crate::sync::{channel, Receiver, Sender};
+
+/// Bytes a pipe buffers before writers block.
+const PIPE_CAPACITY: usize = 4096;
+
+struct PipeReader(Receiver<u8>);
+
+struct PipeWriter(Sender<u8>);
+
+impl File for PipeReader {
+    /// Gives end of file once all write ends are closed and the pipe is empty.
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(self.0.recv_slice(buf).unwrap_or(0))
+    }
+
+    fn write(&self, _data: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::BadFileDescriptor)
+    }
+}
+
+impl File for PipeWriter {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::BadFileDescriptor)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, FsError> {
+        self.0.send_slice(data).map_err(|_| FsError::BrokenPipe)
+    }
+}
+
+/// Creates a pipe, and returns its read end and its write end.
+pub fn pipe() -> (Arc<dyn File>, Arc<dyn File>) {
+    let (sender, receiver) = channel(PIPE_CAPACITY);
+    (Arc::new(PipeReader(receiver)), Arc::new(PipeWriter(sender)))
+}